@@ -0,0 +1,465 @@
+use crate::breaker;
+use crate::cache;
+use crate::error::AppError;
+use crate::routes::Route;
+use crate::secrets;
+use crate::vault;
+use crate::AppState;
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use tokio::sync::Semaphore;
+use tracing_subscriber::EnvFilter;
+use vaultrs::error::ClientError;
+
+#[derive(Deserialize)]
+pub struct LogLevel {
+    filter: String,
+}
+
+/// Replaces the active log filter until the next reload or restart.
+pub async fn set_log_level(
+    State(state): State<AppState>,
+    body: Result<Json<LogLevel>, JsonRejection>,
+) -> Result<Json<Value>, AppError> {
+    let Json(body) =
+        body.map_err(|rejection| AppError::body(rejection, state.settings.load().max_body_bytes))?;
+    let filter = EnvFilter::try_new(&body.filter).map_err(|e| {
+        AppError::BadRequest(format!("invalid log filter {:?}: {}", body.filter, e))
+    })?;
+    state
+        .log_filter
+        .reload(filter)
+        .map_err(|e| AppError::Internal(e.into()))?;
+    tracing::warn!("log filter set to {:?}", body.filter);
+    Ok(Json(json!({ "filter": body.filter })))
+}
+
+/// The effective configuration with credentials masked, and where each field was set.
+pub async fn config(State(state): State<AppState>) -> Json<Value> {
+    let settings = state.settings.load();
+    Json(json!({
+        "settings": settings.redacted(),
+        "sources": settings.sources,
+    }))
+}
+
+/// The paths and methods spot serves, as registered in [`crate::app`].
+pub async fn routes(Extension(routes): Extension<Arc<Vec<Route>>>) -> Json<Value> {
+    Json(json!({ "routes": *routes }))
+}
+
+/// Renews the Vault token, or logs in again, without waiting for the background schedule.
+pub async fn renew_vault_token(State(state): State<AppState>) -> Result<Json<Value>, AppError> {
+    let settings = state.settings.load();
+    let auth = settings
+        .vault_auth
+        .as_ref()
+        .ok_or_else(|| AppError::BadRequest("no Vault auth method configured".into()))?;
+    let lease = state
+        .renewer
+        .renew(&state.vault, auth)
+        .await
+        .map_err(|e| AppError::Internal(e.context("forced Vault token renewal failed")))?;
+    tracing::warn!(
+        "Vault token renewed on demand, lease duration {}s",
+        lease.ttl.as_secs()
+    );
+    Ok(Json(json!({
+        "ttl_secs": lease.ttl.as_secs(),
+        "renewable": lease.renewable,
+    })))
+}
+
+/// The current token's remaining TTL as reported by Vault, when spot last renewed it and the
+/// state of the circuit breaker; the token is left null while the circuit is open.
+pub async fn vault_status(State(state): State<AppState>) -> Result<Json<Value>, AppError> {
+    let token = {
+        let client = state.vault.load_full();
+        match vault::call("token.lookup_self", vaultrs::token::lookup_self(&*client)).await {
+            Ok(token) => Some(token),
+            Err(e) if vault::is_circuit_open(&e) => None,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let renewed_at = state
+        .renewer
+        .renewed_at()
+        .and_then(|at| at.format(&Rfc3339).ok());
+    Ok(Json(json!({
+        "ttl_secs": token.as_ref().map(|token| token.ttl),
+        "renewable": token.as_ref().map(|token| token.renewable),
+        "expire_time": token.and_then(|token| token.expire_time),
+        "last_renewal": renewed_at,
+        "circuit_breaker": breaker::get().map(|breaker| breaker.state()),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct Invalidate {
+    paths: Option<Vec<String>>,
+    #[serde(default)]
+    all: bool,
+}
+
+/// Evicts `paths` from the secret cache in every namespace, or everything for `{"all":true}`
+/// or an empty body; `paths` are validated like those of secret requests.
+pub async fn invalidate_cache(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<Value>, AppError> {
+    let request = if body.is_empty() {
+        Invalidate {
+            paths: None,
+            all: true,
+        }
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| AppError::BadRequest(format!("invalid request body: {}", e)))?
+    };
+
+    let evicted = match (&state.cache, request) {
+        (None, _) => 0,
+        (Some(cache), Invalidate { all: true, .. }) => cache::clear(cache).await,
+        (
+            Some(cache),
+            Invalidate {
+                paths: Some(paths), ..
+            },
+        ) => {
+            for path in &paths {
+                secrets::validate_path(&state, path)?;
+            }
+            cache::evict(cache, &paths).await
+        }
+        (Some(_), _) => {
+            return Err(AppError::BadRequest(
+                "expected paths to evict or all set to true".into(),
+            ))
+        }
+    };
+    tracing::warn!("evicted {} secrets from the cache", evicted);
+    Ok(Json(json!({ "evicted": evicted })))
+}
+
+/// Whether each of `required_secrets` can still be read from Vault, bypassing the cache,
+/// without returning the secrets; 503 when any cannot.
+pub async fn secrets_health(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let settings = state.settings.load();
+    let limit = Duration::from_secs(settings.secrets_health_timeout_secs);
+    let permits = Semaphore::new(settings.batch_concurrency.max(1));
+    let (permits, store, mount) = (&permits, &state.store, &settings.vault_mount);
+    let checks =
+        futures::future::join_all(settings.required_secrets.iter().map(|path| async move {
+            let _permit = permits.acquire().await.expect("semaphore is never closed");
+            let error = match tokio::time::timeout(limit, store.read(None, mount, path)).await {
+                Ok(Ok(_)) => None,
+                Ok(Err(ClientError::APIError { code: 404, .. })) => Some("missing".to_owned()),
+                Ok(Err(e)) => Some(vault::reason(e)),
+                Err(_) => Some(format!("timed out after {}s", limit.as_secs())),
+            };
+            (path, error)
+        }))
+        .await;
+
+    let up = checks.iter().all(|(_, error)| error.is_none());
+    let secrets: Map<String, Value> = checks
+        .into_iter()
+        .map(|(path, error)| {
+            let check = match error {
+                None => json!({ "status" : "ok" }),
+                Some(error) => json!({ "status" : "error", "error" : error }),
+            };
+            (path.clone(), check)
+        })
+        .collect();
+    let (status, aggregate) = if up {
+        (StatusCode::OK, "UP")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "DOWN")
+    };
+    (
+        status,
+        Json(json!({ "status" : aggregate, "secrets" : secrets })),
+    )
+}
+
+/// Starts the same graceful shutdown as SIGTERM; readiness reports DOWN from now on.
+pub async fn shutdown(State(state): State<AppState>) -> StatusCode {
+    tracing::warn!("shutdown requested through the admin API, starting graceful shutdown");
+    state.shutdown.cancel();
+    StatusCode::ACCEPTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, fake_vault, send, test_settings, vault_response, Settings};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::Arc;
+
+    fn state() -> AppState {
+        AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()))
+    }
+
+    fn put_filter(filter: &str) -> Request<Body> {
+        Request::put("/admin/log-level")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "filter" : filter }).to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_log_filter_is_rejected() {
+        let (status, body) = send(app(state()), put_filter("spot=loud")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn valid_log_filter_is_applied() {
+        let (status, body) = send(app(state()), put_filter("spot=trace")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "filter" : "spot=trace" }));
+    }
+
+    #[tokio::test]
+    async fn forced_renewal_returns_the_new_lease() {
+        let vault = axum::Router::new().route(
+            "/v1/auth/token/renew-self",
+            axum::routing::post(|| async {
+                let axum::Json(mut response) = vault_response(Value::Null);
+                response["auth"] = json!({
+                    "client_token" : "hvs.token",
+                    "accessor" : "",
+                    "policies" : ["spot"],
+                    "token_policies" : ["spot"],
+                    "metadata" : null,
+                    "lease_duration" : 7200,
+                    "renewable" : true,
+                    "entity_id" : "",
+                    "token_type" : "service",
+                    "orphan" : true,
+                });
+                axum::Json(response)
+            }),
+        );
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            vault_auth: Some(crate::settings::VaultAuth::Token {
+                token: "hvs.token".into(),
+            }),
+            ..test_settings()
+        };
+
+        let request = Request::post("/admin/vault/renew")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(
+            app(AppState::for_tests(
+                settings,
+                Arc::new(MockSecretStore::new()),
+            )),
+            request,
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "ttl_secs" : 7200, "renewable" : true }));
+    }
+
+    #[tokio::test]
+    async fn forced_renewal_needs_an_auth_method() {
+        let request = Request::post("/admin/vault/renew")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(app(state()), request).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["message"], "no Vault auth method configured");
+    }
+
+    #[tokio::test]
+    async fn config_masks_the_api_token() {
+        let settings = Settings {
+            api_token: Some("spot-api-token-value".into()),
+            ..test_settings()
+        };
+        let state = AppState::for_tests(settings, Arc::new(MockSecretStore::new()));
+
+        let request = Request::get("/admin/config").body(Body::empty()).unwrap();
+        let (status, body) = send(app(state), request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["settings"]["api_token"], "****alue");
+        assert!(!body.to_string().contains("spot-api-token-value"));
+    }
+
+    #[tokio::test]
+    async fn vault_status_reports_the_token_ttl() {
+        let vault = axum::Router::new().route(
+            "/v1/auth/token/lookup-self",
+            axum::routing::get(|| async {
+                vault_response(json!({
+                    "accessor" : "8609694a-cdbc-db9b-d345-e782dbb562ed",
+                    "creation_time" : 1714555800,
+                    "creation_ttl" : 3600,
+                    "display_name" : "approle",
+                    "entity_id" : "",
+                    "expire_time" : "2024-05-01T10:30:00Z",
+                    "explicit_max_ttl" : 0,
+                    "id" : "hvs.token",
+                    "issue_time" : "2024-05-01T09:30:00Z",
+                    "num_uses" : 0,
+                    "orphan" : true,
+                    "path" : "auth/approle/login",
+                    "policies" : ["default", "spot"],
+                    "renewable" : true,
+                    "ttl" : 1234,
+                }))
+            }),
+        );
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            ..test_settings()
+        };
+        let state = AppState::for_tests(settings, Arc::new(MockSecretStore::new()));
+
+        let request = Request::get("/admin/vault/status")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(app(state), request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ttl_secs"], 1234);
+        assert_eq!(body["renewable"], true);
+        assert_eq!(body["expire_time"], "2024-05-01T10:30:00Z");
+        assert_eq!(body["last_renewal"], Value::Null);
+    }
+
+    /// A router with `app/db` and `app/api` read into its cache, and the store behind it.
+    async fn cached() -> (axum::Router, Arc<MockSecretStore>) {
+        let store = Arc::new(
+            MockSecretStore::new()
+                .with("app/db", json!({ "password" : "hunter2" }))
+                .with("app/api", json!({ "key" : "abc" })),
+        );
+        let settings = Settings {
+            cache_enabled: true,
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, store.clone()));
+        for path in ["/secret/app/db", "/secret/app/api"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            assert_eq!(send(router.clone(), request).await.0, StatusCode::OK);
+        }
+        (router, store)
+    }
+
+    fn invalidate(body: &str) -> Request<Body> {
+        Request::post("/admin/cache/invalidate")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalidates_the_given_paths() {
+        let (router, store) = cached().await;
+
+        let (status, body) = send(router.clone(), invalidate(r#"{"paths":["app/db"]}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "evicted" : 1 }));
+
+        for path in ["/secret/app/db", "/secret/app/api"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            send(router.clone(), request).await;
+        }
+        // Only the evicted secret is read again
+        assert_eq!(store.reads(), 3);
+    }
+
+    #[tokio::test]
+    async fn invalid_paths_are_not_evicted() {
+        let (router, store) = cached().await;
+
+        let (status, body) = send(
+            router.clone(),
+            invalidate(r#"{"paths":["app/db","app/../sys"]}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_request");
+
+        let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        send(router, request).await;
+        assert_eq!(store.reads(), 2);
+    }
+
+    #[tokio::test]
+    async fn invalidates_everything() {
+        let (router, store) = cached().await;
+
+        let (status, body) = send(router.clone(), invalidate(r#"{"all":true}"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "evicted" : 2 }));
+
+        let request = Request::get("/secret/app/api").body(Body::empty()).unwrap();
+        send(router, request).await;
+        assert_eq!(store.reads(), 3);
+    }
+
+    #[tokio::test]
+    async fn routes_lists_what_the_router_serves() {
+        let request = Request::get("/admin/routes").body(Body::empty()).unwrap();
+        let (status, body) = send(app(state()), request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let routes = body["routes"].as_array().unwrap();
+        let methods = |path: &str| {
+            routes
+                .iter()
+                .find(|route| route["path"] == path)
+                .unwrap_or_else(|| panic!("{} not in {:?}", path, routes))["methods"]
+                .clone()
+        };
+        assert_eq!(methods("/health"), json!(["GET"]));
+        assert_eq!(methods("/secret/*path"), json!(["GET", "PUT", "DELETE"]));
+        assert_eq!(methods("/admin/routes"), json!(["GET"]));
+    }
+
+    #[tokio::test]
+    async fn secrets_health_is_down_naming_the_unreadable_secret() {
+        let store = MockSecretStore::new()
+            .with("app/db", json!({ "password" : "hunter2" }))
+            .with("app/api", json!({ "key" : "sk-live" }))
+            .failing("app/api", 500);
+        let settings = Settings {
+            required_secrets: vec!["app/db".into(), "app/api".into()],
+            ..test_settings()
+        };
+        let state = AppState::for_tests(settings, Arc::new(store));
+
+        let request = Request::get("/admin/secrets/health")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(app(state), request).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "DOWN");
+        assert_eq!(body["secrets"]["app/db"], json!({ "status" : "ok" }));
+        assert_eq!(body["secrets"]["app/api"]["status"], "error");
+        assert!(!body.to_string().contains("hunter2"));
+    }
+}
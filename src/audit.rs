@@ -0,0 +1,141 @@
+//! The audit trail of secret accesses, written apart from the application logs so that no
+//! log filter can suppress it.
+
+use crate::settings::AuditSink;
+use anyhow::Context;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// One line of the audit trail.
+#[derive(Serialize)]
+pub struct Record<'a> {
+    pub method: &'a str,
+    pub mount: &'a str,
+    pub path: &'a str,
+    pub namespace: Option<&'a str>,
+    pub subject: Option<&'a str>,
+    pub client_cert: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+    /// `success`, or the error code returned to the caller.
+    pub outcome: &'a str,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    record: &'a Record<'a>,
+}
+
+/// Writes records as JSON lines to stdout or to a file opened for appending.
+pub struct AuditLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AuditLog {
+    pub fn open(sink: &AuditSink) -> anyhow::Result<Self> {
+        let out: Box<dyn Write + Send> = match sink {
+            AuditSink::Stdout => Box::new(std::io::stdout()),
+            AuditSink::File { path } => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open audit log {}", path.display()))?,
+            ),
+        };
+        Ok(AuditLog::new(out))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        AuditLog {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn write(&self, record: &Record) {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let mut line = serde_json::to_vec(&Line { timestamp, record })
+            .expect("audit records serialize to JSON");
+        line.push(b'\n');
+
+        // One write per line so that records from concurrent requests never interleave
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(&line).and_then(|()| out.flush()) {
+            tracing::error!("failed to write audit record: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Buffer;
+    use tracing_subscriber::filter::LevelFilter;
+
+    #[test]
+    fn records_are_written_whatever_the_log_filter() {
+        let buffer = Buffer::default();
+        let log = AuditLog::new(Box::new(buffer.clone()));
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(LevelFilter::ERROR)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            log.write(&Record {
+                method: "GET",
+                mount: "secret",
+                path: "app/db",
+                namespace: None,
+                subject: Some("billing"),
+                client_cert: None,
+                client_ip: Some("203.0.113.7".parse().unwrap()),
+                outcome: "success",
+            })
+        });
+
+        let line: serde_json::Value = serde_json::from_str(&buffer.contents()).unwrap();
+        assert_eq!(line["path"], "app/db");
+        assert_eq!(line["subject"], "billing");
+        assert_eq!(line["client_ip"], "203.0.113.7");
+        assert_eq!(line["outcome"], "success");
+        assert!(line["timestamp"].is_string());
+    }
+
+    #[test]
+    fn file_sink_appends_one_line_per_record() {
+        let path = std::env::temp_dir().join(format!("spot-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::open(&AuditSink::File { path: path.clone() }).unwrap();
+
+        for outcome in ["success", "not_found"] {
+            log.write(&Record {
+                method: "GET",
+                mount: "secret",
+                path: "app/db",
+                namespace: Some("team-a"),
+                subject: None,
+                client_cert: None,
+                client_ip: None,
+                outcome,
+            });
+        }
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["namespace"], "team-a");
+        assert_eq!(lines[1]["outcome"], "not_found");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use crate::client_ip::ClientIp;
+use crate::error::error_response;
+use crate::jwt::{Claims, Rejection};
+use crate::mtls::ClientCert;
+use crate::timing;
+use crate::AppState;
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+
+/// Who made a request, as far as spot knows, for the audit log.
+pub struct Caller {
+    pub ip: Option<IpAddr>,
+    /// The `sub` claim of a validated JWT.
+    pub subject: Option<String>,
+    /// The subject of the client certificate, under mutual TLS.
+    pub client_cert: Option<String>,
+}
+
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for Caller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(Caller {
+            ip: parts.extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip),
+            subject: parts
+                .extensions
+                .get::<Claims>()
+                .and_then(|Claims(claims)| claims.get("sub"))
+                .and_then(|sub| sub.as_str())
+                .map(str::to_owned),
+            client_cert: parts
+                .extensions
+                .get::<ClientCert>()
+                .map(|ClientCert(subject)| subject.clone()),
+        })
+    }
+}
+
+/// Rejects requests without a valid `Authorization: Bearer` token with 401.
+///
+/// `health_path` and `ready_path` are served without a token so that liveness and readiness
+/// probes keep working.
+///
+/// Tokens are validated as JWTs when `jwt` is configured and compared to `api_token`
+/// otherwise. Validated JWT claims are attached as a [`crate::jwt::Claims`] extension.
+pub async fn require_token<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = state.settings.load();
+    let path = req.uri().path();
+    if !settings.auth_enabled || path == settings.health_path || path == settings.ready_path {
+        return next.run(req).await;
+    }
+
+    let Some(token) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return unauthorized("missing bearer token");
+    };
+
+    let started = Instant::now();
+    if let Some(jwks) = &state.jwks {
+        let validated = jwks.validate(token);
+        timing::record("auth_check", started.elapsed());
+        match validated {
+            Ok(claims) => {
+                tracing::debug!(subject = ?claims.0.get("sub"), "authenticated JWT");
+                req.extensions_mut().insert(claims);
+                next.run(req).await
+            }
+            Err(Rejection::Invalid(reason)) => {
+                tracing::debug!("rejected JWT: {}", reason);
+                unauthorized("invalid bearer token")
+            }
+            Err(Rejection::WrongAudience) => error_response(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "token was not issued for this audience",
+            ),
+        }
+    } else {
+        let valid = settings
+            .api_token
+            .as_ref()
+            .is_some_and(|expected| bool::from(token.as_bytes().ct_eq(expected.as_bytes())));
+        timing::record("auth_check", started.elapsed());
+        if valid {
+            next.run(req).await
+        } else {
+            unauthorized("invalid bearer token")
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, "unauthorized", message);
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings, Settings};
+    use axum::body::Body;
+    use std::sync::Arc;
+
+    fn router() -> axum::Router {
+        let settings = Settings {
+            auth_enabled: true,
+            api_token: Some("letmein".into()),
+            ..test_settings()
+        };
+        app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ))
+    }
+
+    fn get(uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_unauthorized() {
+        let response = tower::ServiceExt::oneshot(router(), get("/version", None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn wrong_token_is_unauthorized() {
+        let (status, body) = send(router(), get("/version", Some("guess"))).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn correct_token_is_accepted() {
+        let (status, body) = send(router(), get("/version", Some("letmein"))).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn probes_need_no_token() {
+        for uri in ["/health", "/ready"] {
+            let (status, _) = send(router(), get(uri, None)).await;
+
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+    }
+}
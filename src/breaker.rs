@@ -0,0 +1,238 @@
+//! Fails Vault calls fast while Vault keeps failing, instead of letting every request wait
+//! for its own timeout.
+
+use crate::settings::CircuitBreaker;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static BREAKER: OnceLock<Breaker> = OnceLock::new();
+
+/// Puts every later [`crate::vault::call`] behind a breaker configured by `config`.
+pub fn install(config: &CircuitBreaker) {
+    let breaker = BREAKER.get_or_init(|| Breaker::new(config));
+    publish(breaker.state());
+}
+
+/// The installed breaker, if `circuit_breaker` is configured.
+pub fn get() -> Option<&'static Breaker> {
+    BREAKER.get()
+}
+
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        since: Instant,
+    },
+    /// One call is probing whether Vault has recovered.
+    HalfOpen,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl Breaker {
+    fn new(config: &CircuitBreaker) -> Self {
+        Breaker {
+            threshold: config.failure_threshold,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// `closed`, `open` or `half_open`.
+    pub fn state(&self) -> &'static str {
+        self.lock().name()
+    }
+
+    /// Lets a call through, unless the circuit is open or another call is already probing.
+    ///
+    /// Once the cooldown is over, the first call admitted is the probe.
+    pub fn admit(&'static self) -> Option<Admission> {
+        let mut state = self.lock();
+        match *state {
+            State::Closed { .. } => Some(Admission {
+                breaker: self,
+                probe: false,
+            }),
+            State::Open { since } if since.elapsed() >= self.cooldown => {
+                self.transition(&mut state, State::HalfOpen);
+                Some(Admission {
+                    breaker: self,
+                    probe: true,
+                })
+            }
+            State::Open { .. } | State::HalfOpen => None,
+        }
+    }
+
+    fn finish(&self, probe: bool, healthy: bool) {
+        let mut state = self.lock();
+        match (*state, healthy) {
+            (State::Closed { .. }, true) => *state = State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 >= self.threshold => {
+                self.transition(
+                    &mut state,
+                    State::Open {
+                        since: Instant::now(),
+                    },
+                );
+            }
+            (State::Closed { failures }, false) => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::HalfOpen, true) if probe => {
+                self.transition(&mut state, State::Closed { failures: 0 })
+            }
+            (State::HalfOpen, false) if probe => self.transition(
+                &mut state,
+                State::Open {
+                    since: Instant::now(),
+                },
+            ),
+            // Calls admitted before the circuit opened say nothing about the probe
+            _ => {}
+        }
+    }
+
+    fn transition(&self, state: &mut State, next: State) {
+        match next {
+            State::Open { .. } => tracing::warn!(
+                "Vault circuit breaker is open, failing Vault calls for {}s",
+                self.cooldown.as_secs()
+            ),
+            State::HalfOpen => tracing::info!("Vault circuit breaker is half-open, probing Vault"),
+            State::Closed { .. } => {
+                tracing::warn!("Vault circuit breaker is closed, Vault recovered")
+            }
+        }
+        *state = next;
+        publish(next.name());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Sets `vault_circuit_breaker_state` to 1 for `current` and 0 for the other states.
+fn publish(current: &'static str) {
+    for name in ["closed", "open", "half_open"] {
+        metrics::gauge!(
+            "vault_circuit_breaker_state",
+            if name == current { 1.0 } else { 0.0 },
+            "state" => name
+        );
+    }
+}
+
+/// A call let through by [`Breaker::admit`], whose outcome is reported with
+/// [`Admission::finish`]; a probe dropped before finishing counts as failed.
+pub struct Admission {
+    breaker: &'static Breaker,
+    probe: bool,
+}
+
+impl Admission {
+    /// Records whether Vault answered, even with a client error such as 404.
+    pub fn finish(self, healthy: bool) {
+        self.breaker.finish(self.probe, healthy);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.finish(true, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A breaker of its own, rather than the installed one shared by the process.
+    fn breaker(failure_threshold: u32) -> &'static Breaker {
+        let config = CircuitBreaker {
+            failure_threshold,
+            cooldown_secs: 0,
+        };
+        Box::leak(Box::new(Breaker {
+            cooldown: Duration::from_millis(50),
+            ..Breaker::new(&config)
+        }))
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(2);
+
+        breaker.admit().unwrap().finish(false);
+        assert_eq!(breaker.state(), "closed");
+        breaker.admit().unwrap().finish(false);
+
+        assert_eq!(breaker.state(), "open");
+        assert!(breaker.admit().is_none());
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(2);
+
+        breaker.admit().unwrap().finish(false);
+        breaker.admit().unwrap().finish(true);
+        breaker.admit().unwrap().finish(false);
+
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn recovers_through_a_probe_after_the_cooldown() {
+        let breaker = breaker(1);
+        breaker.admit().unwrap().finish(false);
+        assert_eq!(breaker.state(), "open");
+
+        std::thread::sleep(Duration::from_millis(60));
+        let probe = breaker.admit().expect("the probe is let through");
+        assert_eq!(breaker.state(), "half_open");
+        // Only the probe goes through until it reports back
+        assert!(breaker.admit().is_none());
+        probe.finish(true);
+
+        assert_eq!(breaker.state(), "closed");
+        assert!(breaker.admit().is_some());
+    }
+
+    #[test]
+    fn failed_or_abandoned_probe_opens_the_circuit_again() {
+        let breaker = breaker(1);
+        breaker.admit().unwrap().finish(false);
+
+        std::thread::sleep(Duration::from_millis(60));
+        breaker.admit().unwrap().finish(false);
+        assert_eq!(breaker.state(), "open");
+
+        std::thread::sleep(Duration::from_millis(60));
+        drop(breaker.admit().unwrap());
+        assert_eq!(breaker.state(), "open");
+    }
+}
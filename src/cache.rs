@@ -0,0 +1,237 @@
+use crate::settings::Settings;
+use crate::store::SecretStore;
+use crate::vault;
+use futures::StreamExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Namespace override, mount and path.
+pub type Key = (Option<String>, String, String);
+
+/// Secrets read from Vault.
+#[derive(Clone)]
+pub struct SecretCache {
+    secrets: moka::future::Cache<Key, Value>,
+    /// How many times secrets have been changed or evicted through spot, so that a read that
+    /// was already under way when one was does not cache what it read.
+    generations: Arc<Mutex<Generations>>,
+}
+
+/// Counted by path rather than by [`Key`], as evicting covers every namespace and mount.
+#[derive(Default)]
+struct Generations {
+    cleared: u64,
+    paths: HashMap<String, u64>,
+}
+
+impl SecretCache {
+    pub async fn get(&self, key: &Key) -> Option<Value> {
+        self.secrets.get(key).await
+    }
+
+    /// Taken before reading `key` from Vault, to [`SecretCache::insert`] what was read.
+    pub fn generation(&self, key: &Key) -> u64 {
+        let generations = self.generations();
+        generations.cleared + generations.paths.get(&key.2).copied().unwrap_or_default()
+    }
+
+    /// Caches `secret` unless `key` has changed since `generation`.
+    pub async fn insert(&self, key: Key, secret: Value, generation: u64) {
+        self.secrets.insert(key.clone(), secret).await;
+        // Checked after inserting, since a change finishing before the check is caught here
+        // and one finishing after it evicts what was inserted
+        if self.generation(&key) != generation {
+            self.secrets.invalidate(&key).await;
+        }
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.secrets.entry_count()
+    }
+
+    /// Keeps reads of `path` already under way from caching what they read.
+    fn changed(&self, path: &str) {
+        *self.generations().paths.entry(path.to_owned()).or_default() += 1;
+    }
+
+    fn generations(&self) -> std::sync::MutexGuard<'_, Generations> {
+        self.generations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub fn new(settings: &Settings) -> SecretCache {
+    SecretCache {
+        secrets: moka::future::Cache::builder()
+            .max_capacity(settings.cache_max_capacity)
+            .time_to_live(Duration::from_secs(settings.cache_ttl_secs))
+            .build(),
+        generations: Arc::default(),
+    }
+}
+
+pub fn key(namespace: Option<&str>, mount: &str, path: &str) -> Key {
+    (
+        namespace.map(str::to_owned),
+        mount.to_owned(),
+        path.to_owned(),
+    )
+}
+
+/// Reads `paths` into the cache, at most `concurrency` at a time, failing with every path
+/// that could not be read once all of them have been tried.
+pub async fn preload(
+    cache: &SecretCache,
+    store: &dyn SecretStore,
+    mount: &str,
+    paths: &[String],
+    concurrency: usize,
+) -> anyhow::Result<()> {
+    let failed: Vec<String> = futures::stream::iter(paths)
+        .map(|path| async move {
+            let key = key(None, mount, path);
+            let generation = cache.generation(&key);
+            match store.read(None, mount, path).await {
+                Ok(secret) => {
+                    cache.insert(key, secret, generation).await;
+                    None
+                }
+                Err(e) => Some(format!("{} ({})", path, vault::reason(e))),
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|failure| async { failure })
+        .collect()
+        .await;
+    if !failed.is_empty() {
+        anyhow::bail!("failed to preload secrets: {}", failed.join(", "));
+    }
+    tracing::info!("preloaded {} secrets into the cache", paths.len());
+    Ok(())
+}
+
+/// Drops the cached copies of `paths`, in every namespace and mount, returning how many there
+/// were.
+pub async fn evict(cache: &SecretCache, paths: &[String]) -> u64 {
+    for path in paths {
+        cache.changed(path);
+    }
+    let keys: Vec<_> = cache
+        .secrets
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| paths.contains(&key.2))
+        .collect();
+    let mut evicted = 0;
+    for key in keys {
+        if cache.secrets.remove(&*key).await.is_some() {
+            evicted += 1;
+        }
+    }
+    evicted
+}
+
+/// Empties the cache, returning how many secrets it held.
+pub async fn clear(cache: &SecretCache) -> u64 {
+    cache.generations().cleared += 1;
+    cache.secrets.run_pending_tasks().await;
+    let evicted = cache.secrets.entry_count();
+    cache.secrets.invalidate_all();
+    evicted
+}
+
+/// Drops a cached secret after it has been changed in Vault.
+pub async fn invalidate(
+    cache: &Option<SecretCache>,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+) {
+    if let Some(cache) = cache {
+        cache.changed(path);
+        cache.secrets.invalidate(&key(namespace, mount, path)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings, AppState};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn preloaded_secrets_are_served_without_reading_the_store() {
+        let store = Arc::new(
+            MockSecretStore::new()
+                .with("app/db", json!({ "password" : "hunter2" }))
+                .with("app/api", json!({ "key" : "abc" })),
+        );
+        let settings = Settings {
+            cache_enabled: true,
+            ..test_settings()
+        };
+        let state = AppState::for_tests(settings, store.clone());
+        let paths = ["app/db".to_owned(), "app/api".to_owned()];
+
+        preload(state.cache.as_ref().unwrap(), &*store, "secret", &paths, 2)
+            .await
+            .unwrap();
+        assert_eq!(store.reads(), 2);
+
+        let router = app(state);
+        for path in &paths {
+            let request = Request::get(format!("/secret/{}", path))
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(send(router.clone(), request).await.0, StatusCode::OK);
+        }
+        assert_eq!(store.reads(), 2);
+    }
+
+    #[tokio::test]
+    async fn preload_fails_naming_the_unreadable_paths() {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let settings = Settings {
+            cache_enabled: true,
+            ..test_settings()
+        };
+        let paths = ["app/db".to_owned(), "app/api".to_owned()];
+
+        let error = preload(&new(&settings), &store, "secret", &paths, 2)
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("app/api"), "{}", error);
+        assert!(!error.to_string().contains("app/db"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn reads_under_way_when_the_cache_is_invalidated_are_not_cached() {
+        let cache = new(&Settings::default());
+        let key = key(Some("team-a"), "kv", "app/db");
+
+        let generation = cache.generation(&key);
+        evict(&cache, &["app/db".to_owned()]).await;
+        cache
+            .insert(key.clone(), json!({ "password" : "old" }), generation)
+            .await;
+        assert_eq!(cache.get(&key).await, None);
+
+        let generation = cache.generation(&key);
+        clear(&cache).await;
+        cache
+            .insert(key.clone(), json!({ "password" : "old" }), generation)
+            .await;
+        assert_eq!(cache.get(&key).await, None);
+
+        let generation = cache.generation(&key);
+        cache
+            .insert(key.clone(), json!({ "password" : "new" }), generation)
+            .await;
+        assert_eq!(cache.get(&key).await, Some(json!({ "password" : "new" })));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// The address of the client on whose behalf the request was made.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Attaches [`ClientIp`], believing forwarding headers only from `trusted_proxies`.
+pub async fn resolve<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+    if let Some(peer) = peer {
+        let client = client_ip(peer, req.headers(), &state.settings.load().trusted_proxies);
+        req.extensions_mut().insert(ClientIp(client));
+    }
+    next.run(req).await
+}
+
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        // An unreadable header is an unparseable hop, which stops the walk below
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(str::trim)
+        .collect();
+    if !hops.is_empty() {
+        // Each proxy appends the address it received from, so walk back from the nearest hop
+        // to the first one not added by a trusted proxy; anything further left is whatever
+        // the client chose to send
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            match hop.parse() {
+                Ok(ip) if is_trusted(&ip) => client = ip,
+                Ok(ip) => return ip,
+                Err(_) => break,
+            }
+        }
+        return client;
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn walks_a_trusted_chain_to_the_client() {
+        let headers = forwarded_for("203.0.113.7, 10.0.0.2, 10.0.0.3");
+        assert_eq!(
+            client_ip(ip("10.0.0.4"), &headers, &trusted()),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_an_untrusted_peer() {
+        let headers = forwarded_for("203.0.113.7");
+        assert_eq!(
+            client_ip(ip("198.51.100.1"), &headers, &trusted()),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_x_real_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(
+            client_ip(ip("10.0.0.4"), &headers, &trusted()),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn ignores_hops_the_client_prepended() {
+        // The client sent `X-Forwarded-For: 192.0.2.1` and the proxy appended its address
+        let headers = forwarded_for("192.0.2.1, 203.0.113.7");
+        assert_eq!(
+            client_ip(ip("10.0.0.4"), &headers, &trusted()),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn stops_at_an_unparseable_hop() {
+        let headers = forwarded_for("192.0.2.1, garbage, 10.0.0.2");
+        assert_eq!(
+            client_ip(ip("10.0.0.4"), &headers, &trusted()),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn a_fully_trusted_chain_yields_the_leftmost_proxy() {
+        let headers = forwarded_for("10.0.0.1, 10.0.0.2");
+        assert_eq!(
+            client_ip(ip("10.0.0.4"), &headers, &trusted()),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Single-flight execution, so that identical concurrent requests share one call to Vault.
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Calls in progress by key; each is removed once done, whether it succeeded or failed, or
+/// once every caller waiting for it has gone away.
+pub struct SingleFlight<K, T: Clone> {
+    calls: Mutex<HashMap<K, Call<T>>>,
+}
+
+struct Call<T: Clone> {
+    /// Never polled itself, so that it still identifies the call once others have finished it.
+    future: Shared<BoxFuture<'static, T>>,
+    callers: usize,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone + Send + Sync + 'static> SingleFlight<K, T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `call` unless a call for `key` is already in progress, in which case its result
+    /// is waited for and shared instead.
+    ///
+    /// `call` is polled by whichever caller gets to it, so it must not depend on anything
+    /// particular to the caller that started it, such as task-locals.
+    pub async fn run<F>(&self, key: K, call: F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let future = {
+            let mut calls = self.calls();
+            let call = calls.entry(key.clone()).or_insert_with(|| Call {
+                future: call.boxed().shared(),
+                callers: 0,
+            });
+            call.callers += 1;
+            call.future.clone()
+        };
+        let _caller = Caller {
+            flight: self,
+            key,
+            future: future.clone(),
+        };
+        future.await
+    }
+
+    fn calls(&self) -> std::sync::MutexGuard<'_, HashMap<K, Call<T>>> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Removes the call from the map when the last caller waiting for it finishes or is dropped.
+struct Caller<'a, K: Eq + Hash + Clone, T: Clone + Send + Sync + 'static> {
+    flight: &'a SingleFlight<K, T>,
+    key: K,
+    future: Shared<BoxFuture<'static, T>>,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone + Send + Sync + 'static> Drop for Caller<'_, K, T> {
+    fn drop(&mut self) {
+        let mut calls = self.flight.calls();
+        let Some(call) = calls
+            .get_mut(&self.key)
+            .filter(|call| Shared::ptr_eq(&call.future, &self.future))
+        else {
+            // Already replaced by a later call for the same key
+            return;
+        };
+        call.callers -= 1;
+        // A finished call must not serve later callers; an abandoned one is dropped unfinished
+        if call.callers == 0 || call.future.peek().is_some() {
+            calls.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_one_call() {
+        let flight = SingleFlight::new();
+        let started = Arc::new(AtomicUsize::new(0));
+        let outputs = futures::future::join_all((0..10).map(|_| {
+            let started = started.clone();
+            flight.run("key", async move {
+                started.fetch_add(1, Ordering::SeqCst);
+                // Time only moves on once every caller is waiting
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                42
+            })
+        }))
+        .await;
+
+        assert_eq!(outputs, [42; 10]);
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        assert!(flight.calls().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abandoned_call_is_removed() {
+        let flight = Arc::new(SingleFlight::new());
+        let leader = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.run("key", futures::future::pending::<u32>()).await }
+        });
+        // Time only moves on once the leader is waiting
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(flight.calls().len(), 1);
+
+        leader.abort();
+        let _ = leader.await;
+
+        assert!(flight.calls().is_empty());
+        assert_eq!(flight.run("key", async { 7 }).await, 7);
+    }
+}
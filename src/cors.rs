@@ -0,0 +1,98 @@
+use crate::settings::Cors;
+use anyhow::{bail, Context};
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Builds the layer, failing on entries that are not valid origins, methods or headers.
+pub fn layer(config: &Cors) -> anyhow::Result<CorsLayer> {
+    let wildcard = config.allowed_origins.iter().any(|origin| origin == "*");
+    if wildcard && config.allow_credentials {
+        bail!("cors.allow_credentials cannot be combined with a wildcard origin");
+    }
+
+    let origins = if wildcard {
+        AllowOrigin::from(Any)
+    } else {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("invalid CORS origin {:?}", origin))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            method
+                .parse::<Method>()
+                .with_context(|| format!("invalid CORS method {:?}", method))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let headers = config
+        .allowed_headers
+        .iter()
+        .map(|header| {
+            header
+                .parse::<HeaderName>()
+                .with_context(|| format!("invalid CORS header {:?}", header))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(config.allow_credentials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, test_settings, AppState, Settings};
+    use axum::{body::Body, http::header, http::Request, http::StatusCode};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn preflight_gets_the_configured_cors_headers() {
+        let settings = Settings {
+            cors: Some(Cors {
+                allowed_origins: vec!["https://console.example".into()],
+                allowed_methods: vec!["GET".into(), "PUT".into()],
+                allowed_headers: vec!["authorization".into()],
+                allow_credentials: true,
+            }),
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ));
+        let preflight = Request::options("/secret/app/db")
+            .header(header::ORIGIN, "https://console.example")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router.oneshot(preflight).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://console.example"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET,PUT");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+}
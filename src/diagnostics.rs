@@ -0,0 +1,85 @@
+//! A snapshot of what the process is doing, logged on SIGQUIT much like a Go runtime dump but
+//! without exiting.
+
+use crate::breaker;
+use crate::AppState;
+use serde_json::{json, Map, Value};
+use std::sync::atomic::Ordering;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Logs a [`snapshot`] whenever SIGQUIT is received.
+pub async fn on_sigquit(state: AppState, tasks: TaskTracker, shutdown: CancellationToken) {
+    let mut quit = signal(SignalKind::quit()).expect("failed to install SIGQUIT handler");
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = quit.recv() => {}
+        }
+        tracing::warn!(snapshot = %snapshot(&state, &tasks), "SIGQUIT received");
+    }
+}
+
+/// In-flight requests, the state of the background tasks and the Tokio runtime's counters.
+pub fn snapshot(state: &AppState, tasks: &TaskTracker) -> Value {
+    let lease = state.renewer.lease();
+    let readiness: Map<String, Value> = state
+        .readiness
+        .last_statuses()
+        .into_iter()
+        .map(|(name, up)| {
+            let status = match up {
+                Some(true) => "UP",
+                Some(false) => "DOWN",
+                None => "unknown",
+            };
+            (name.to_owned(), json!(status))
+        })
+        .collect();
+
+    let runtime = tokio::runtime::Handle::current().metrics();
+    json!({
+        "in_flight_requests" : state.requests.len(),
+        "shutting_down" : state.shutdown.is_cancelled(),
+        "background_tasks" : tasks.len(),
+        "vault" : {
+            "connected" : state.vault_connected.load(Ordering::Acquire),
+            "token_ttl_secs" : lease.map(|lease| lease.ttl.as_secs()),
+            "token_renewable" : lease.map(|lease| lease.renewable),
+            "circuit_breaker" : breaker::get().map(|breaker| breaker.state()),
+        },
+        "readiness" : readiness,
+        "metrics_installed" : state.metrics.is_some(),
+        "cache_entries" : state.cache.as_ref().map(|cache| cache.entry_count()),
+        "runtime" : {
+            "workers" : runtime.num_workers(),
+            "alive_tasks" : runtime.num_alive_tasks(),
+            "global_queue_depth" : runtime.global_queue_depth(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::test_settings;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn snapshot_reports_requests_tasks_and_vault() {
+        let state = AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()));
+        let tasks = TaskTracker::new();
+        let _request = state.requests.track_future(std::future::pending::<()>());
+
+        let snapshot = snapshot(&state, &tasks);
+
+        assert_eq!(snapshot["in_flight_requests"], 1);
+        assert_eq!(snapshot["shutting_down"], false);
+        assert_eq!(snapshot["background_tasks"], 0);
+        assert_eq!(snapshot["vault"]["connected"], true);
+        assert_eq!(snapshot["metrics_installed"], false);
+        assert!(snapshot["runtime"]["workers"].as_u64().unwrap() >= 1);
+    }
+}
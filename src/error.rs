@@ -0,0 +1,276 @@
+use crate::vault;
+use axum::{
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use opentelemetry::trace::TraceContextExt;
+use serde_json::{json, Value};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use vaultrs::error::ClientError;
+
+#[derive(Debug)]
+pub enum AppError {
+    VaultNotFound(String),
+    VaultUnauthorized(String),
+    Upstream(String),
+    BadRequest(String),
+    Forbidden(String),
+    PayloadTooLarge(String),
+    NotAcceptable(String),
+    Timeout(String),
+    /// Vault calls are failing fast while the circuit breaker is open.
+    Unavailable(String),
+    /// Secrets, or keys within them, that a template refers to but that do not exist.
+    MissingSecrets(Vec<String>),
+    /// Fields requested with `?fields=` that the secret does not have.
+    MissingFields(Vec<String>),
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::VaultNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::VaultUnauthorized(_) | AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::MissingSecrets(_) | AppError::MissingFields(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::VaultNotFound(_) => "not_found",
+            AppError::VaultUnauthorized(_) => "vault_unauthorized",
+            AppError::Upstream(_) => "upstream_error",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::NotAcceptable(_) => "not_acceptable",
+            AppError::Timeout(_) => "timeout",
+            AppError::Unavailable(_) => "vault_unavailable",
+            AppError::MissingSecrets(_) => "missing_secrets",
+            AppError::MissingFields(_) => "missing_fields",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+/// Lets one failed Vault call be reported to every request that was waiting on it.
+impl Clone for AppError {
+    fn clone(&self) -> Self {
+        match self {
+            AppError::VaultNotFound(message) => AppError::VaultNotFound(message.clone()),
+            AppError::VaultUnauthorized(message) => AppError::VaultUnauthorized(message.clone()),
+            AppError::Upstream(message) => AppError::Upstream(message.clone()),
+            AppError::BadRequest(message) => AppError::BadRequest(message.clone()),
+            AppError::Forbidden(message) => AppError::Forbidden(message.clone()),
+            AppError::PayloadTooLarge(message) => AppError::PayloadTooLarge(message.clone()),
+            AppError::NotAcceptable(message) => AppError::NotAcceptable(message.clone()),
+            AppError::Timeout(message) => AppError::Timeout(message.clone()),
+            AppError::Unavailable(message) => AppError::Unavailable(message.clone()),
+            AppError::MissingSecrets(missing) => AppError::MissingSecrets(missing.clone()),
+            AppError::MissingFields(missing) => AppError::MissingFields(missing.clone()),
+            AppError::Internal(e) => AppError::Internal(anyhow::anyhow!("{:#}", e)),
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::VaultNotFound(message)
+            | AppError::VaultUnauthorized(message)
+            | AppError::Upstream(message)
+            | AppError::BadRequest(message)
+            | AppError::Forbidden(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::NotAcceptable(message)
+            | AppError::Timeout(message)
+            | AppError::Unavailable(message) => f.write_str(message),
+            AppError::MissingSecrets(missing) => write!(
+                f,
+                "the template refers to missing secrets: {}",
+                missing.join(", ")
+            ),
+            AppError::MissingFields(missing) => {
+                write!(f, "the secret has no field {}", missing.join(", "))
+            }
+            AppError::Internal(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("{}", self);
+        }
+        let mut error = error_detail(self.code(), self.to_string());
+        if let AppError::MissingSecrets(missing) | AppError::MissingFields(missing) = &self {
+            error["missing"] = json!(missing);
+        }
+        (status, Json(json!({ "error" : error }))).into_response()
+    }
+}
+
+impl From<ClientError> for AppError {
+    fn from(error: ClientError) -> Self {
+        match error {
+            ClientError::APIError { code: 404, .. } => {
+                AppError::VaultNotFound(vault::reason(error))
+            }
+            ClientError::APIError {
+                code: 401 | 403, ..
+            } => AppError::VaultUnauthorized(vault::reason(error)),
+            // Also how calls cut short by the request deadline are reported
+            ClientError::APIError { code: 504, errors } if !errors.is_empty() => {
+                AppError::Timeout(errors.join(", "))
+            }
+            ClientError::APIError { code: 504, .. } => AppError::Timeout("Vault timed out".into()),
+            e if vault::is_circuit_open(&e) => AppError::Unavailable(vault::CIRCUIT_OPEN.into()),
+            e if vault::too_large(&e).is_some() => AppError::Upstream(
+                vault::too_large(&e)
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+            ),
+            e => AppError::Upstream(vault::reason(e)),
+        }
+    }
+}
+
+impl AppError {
+    /// Explains a rejected JSON body, naming `limit` when the body was too large.
+    pub fn body(rejection: JsonRejection, limit: usize) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge(format!(
+                "request body exceeds the limit of {} bytes",
+                limit
+            )),
+            _ => AppError::BadRequest(rejection.body_text()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        AppError::Internal(error)
+    }
+}
+
+/// Renders the `{"error":{"code":...,"message":...}}` body shared by every error response.
+///
+/// The error also carries the `trace_id` of the current span, when it is being traced.
+pub fn error_response(status: StatusCode, code: &str, message: impl Into<String>) -> Response {
+    let error = error_detail(code, message.into());
+    (status, Json(json!({ "error" : error }))).into_response()
+}
+
+fn error_detail(code: &str, message: String) -> Value {
+    let mut error = json!({ "code" : code, "message" : message });
+    if let Some(trace_id) = trace_id() {
+        error["trace_id"] = json!(trace_id);
+    }
+    error
+}
+
+fn trace_id() -> Option<String> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings, AppState};
+    use axum::{body::Body, http::Request};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    async fn body(response: Response) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_variant_has_its_status_and_code() {
+        let cases = [
+            (AppError::VaultNotFound("m".into()), 404, "not_found"),
+            (
+                AppError::VaultUnauthorized("m".into()),
+                502,
+                "vault_unauthorized",
+            ),
+            (AppError::Upstream("m".into()), 502, "upstream_error"),
+            (AppError::BadRequest("m".into()), 400, "bad_request"),
+            (AppError::Forbidden("m".into()), 403, "forbidden"),
+            (
+                AppError::PayloadTooLarge("m".into()),
+                413,
+                "payload_too_large",
+            ),
+            (
+                AppError::Internal(anyhow::anyhow!("m")),
+                500,
+                "internal_error",
+            ),
+        ];
+        for (error, status, code) in cases {
+            let response = error.into_response();
+
+            assert_eq!(response.status(), status, "{}", code);
+            assert_eq!(
+                body(response).await,
+                json!({ "error" : { "code" : code, "message" : "m" } })
+            );
+        }
+    }
+
+    #[test]
+    fn vault_errors_map_to_variants() {
+        let api = |code| ClientError::APIError {
+            code,
+            errors: vec![],
+        };
+        assert_eq!(AppError::from(api(404)).code(), "not_found");
+        assert_eq!(AppError::from(api(403)).code(), "vault_unauthorized");
+        assert_eq!(AppError::from(api(500)).code(), "upstream_error");
+    }
+
+    #[tokio::test]
+    async fn error_body_carries_the_trace_id_of_the_request() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("spot-tests")));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let state = AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()));
+        let request = Request::get("/secret/app/missing")
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, body) = send(app(state), request).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let trace_id = body["error"]["trace_id"].as_str().expect("a trace id");
+        assert_eq!(trace_id.len(), 32);
+        assert_ne!(trace_id, "0".repeat(32));
+    }
+}
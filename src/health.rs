@@ -0,0 +1,412 @@
+use crate::{vault, AppState};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use vaultrs::client::VaultClient;
+
+pub enum HealthStatus {
+    Up,
+    Down(String),
+}
+
+/// A dependency that must be reachable for spot to be ready.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// Key for this check in the readiness response.
+    fn name(&self) -> &str;
+
+    async fn check(&self) -> HealthStatus;
+}
+
+pub struct VaultCheck {
+    pub vault: Arc<ArcSwap<VaultClient>>,
+    pub connected: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl HealthCheck for VaultCheck {
+    fn name(&self) -> &str {
+        "vault"
+    }
+
+    async fn check(&self) -> HealthStatus {
+        if !self.connected.load(Ordering::Acquire) {
+            return HealthStatus::Down("not connected to Vault yet".into());
+        }
+        match vault::health(&self.vault.load_full()).await {
+            Ok(()) => HealthStatus::Up,
+            Err(e) => HealthStatus::Down(format!("{:#}", e)),
+        }
+    }
+}
+
+/// The registered checks and their most recent aggregate result.
+pub struct Readiness {
+    checks: Vec<Box<dyn HealthCheck>>,
+    ttl: Duration,
+    last: RwLock<Option<(Instant, StatusCode, Value)>>,
+    /// Whether each check was up when last run, to report only transitions.
+    up: std::sync::Mutex<Vec<Option<bool>>>,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<Box<dyn HealthCheck>>, ttl: Duration) -> Self {
+        Readiness {
+            up: std::sync::Mutex::new(vec![None; checks.len()]),
+            checks,
+            ttl,
+            last: RwLock::new(None),
+        }
+    }
+
+    /// Reuses a result younger than `ttl`; otherwise runs the checks once for all waiting probes.
+    async fn status(&self) -> (StatusCode, Value) {
+        let fresh = |last: &Option<(Instant, StatusCode, Value)>| {
+            last.as_ref()
+                .filter(|(at, _, _)| at.elapsed() < self.ttl)
+                .map(|(_, status, body)| (*status, body.clone()))
+        };
+        if let Some(cached) = fresh(&*self.last.read().await) {
+            return cached;
+        }
+
+        let mut last = self.last.write().await;
+        if let Some(cached) = fresh(&last) {
+            return cached;
+        }
+        let (status, body) = self.check().await;
+        *last = Some((Instant::now(), status, body.clone()));
+        (status, body)
+    }
+
+    /// Runs every check concurrently; ready only when all of them are up.
+    async fn check(&self) -> (StatusCode, Value) {
+        let statuses =
+            futures::future::join_all(self.checks.iter().map(|check| check.check())).await;
+
+        let mut healthy = true;
+        let mut checks = Map::new();
+        for (i, (check, status)) in self.checks.iter().zip(statuses).enumerate() {
+            self.transition(i, &status);
+            let detail = match status {
+                HealthStatus::Up => json!({ "status" : "UP" }),
+                HealthStatus::Down(reason) => {
+                    healthy = false;
+                    json!({ "status" : "DOWN", "reason" : reason })
+                }
+            };
+            checks.insert(check.name().to_string(), detail);
+        }
+
+        let (status, overall) = if healthy {
+            (StatusCode::OK, "UP")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "DOWN")
+        };
+        (
+            status,
+            json!({ "status" : overall, "checks" : Value::Object(checks) }),
+        )
+    }
+
+    /// Each check's status when it last ran, `None` before its first run.
+    pub fn last_statuses(&self) -> Vec<(&str, Option<bool>)> {
+        let up = self.up.lock().unwrap_or_else(|e| e.into_inner());
+        self.checks
+            .iter()
+            .map(|check| check.name())
+            .zip(up.iter().copied())
+            .collect()
+    }
+
+    /// Logs and updates the `readiness_check_up` gauge when check `i` changes status,
+    /// including on its first run.
+    fn transition(&self, i: usize, status: &HealthStatus) {
+        let up = matches!(status, HealthStatus::Up);
+        let mut previous = self.up.lock().unwrap_or_else(|e| e.into_inner());
+        if previous[i] == Some(up) {
+            return;
+        }
+        previous[i] = Some(up);
+
+        let name = self.checks[i].name();
+        match status {
+            HealthStatus::Up => tracing::info!(check = name, "readiness check is UP"),
+            HealthStatus::Down(reason) => {
+                tracing::warn!(check = name, reason = %reason, "readiness check is DOWN")
+            }
+        }
+        metrics::gauge!(
+            "readiness_check_up",
+            if up { 1.0 } else { 0.0 },
+            "check" => name.to_owned()
+        );
+    }
+}
+
+/// Ready when every registered check is up.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    responses(
+        (status = 200, description = "Ready to serve", body = crate::openapi::Health),
+        (status = 503, description = "A check failed or shutdown has begun", body = crate::openapi::Health),
+    )
+)]
+pub async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+    if state.shutdown.is_cancelled() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status" : "DOWN", "reason" : "shutting down" })),
+        );
+    }
+    let (status, body) = state.readiness.status().await;
+    (status, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, fake_vault, send, test_settings, Settings};
+    use axum::{body::Body, http::Request, routing::get, Router};
+
+    fn with_checks(state: AppState, checks: Vec<Box<dyn HealthCheck>>) -> AppState {
+        AppState {
+            readiness: Arc::new(Readiness::new(checks, Duration::ZERO)),
+            ..state
+        }
+    }
+
+    /// A state checking only the Vault at `vault_address`.
+    fn checking_vault(vault_address: String) -> AppState {
+        let state = AppState::for_tests(
+            Settings {
+                vault_address,
+                ..test_settings()
+            },
+            Arc::new(MockSecretStore::new()),
+        );
+        let check = VaultCheck {
+            vault: state.vault.clone(),
+            connected: state.vault_connected.clone(),
+        };
+        with_checks(state, vec![Box::new(check)])
+    }
+
+    /// Down with the reason set, if any.
+    struct FakeCheck {
+        name: &'static str,
+        down: Arc<std::sync::Mutex<Option<String>>>,
+        /// How many times the check has run.
+        runs: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl FakeCheck {
+        fn new(name: &'static str, down: Option<&str>) -> Self {
+            FakeCheck {
+                name,
+                down: Arc::new(std::sync::Mutex::new(down.map(str::to_owned))),
+                runs: Arc::default(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HealthCheck for FakeCheck {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn check(&self) -> HealthStatus {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            match self.down.lock().unwrap().clone() {
+                Some(reason) => HealthStatus::Down(reason),
+                None => HealthStatus::Up,
+            }
+        }
+    }
+
+    fn ready() -> Request<Body> {
+        Request::get("/ready").body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ready_when_vault_is_healthy() {
+        let vault = fake_vault(Router::new().route(
+            "/v1/sys/health",
+            get(|| async {
+                Json(json!({
+                    "cluster_id" : "c1",
+                    "cluster_name" : "vault-cluster",
+                    "initialized" : true,
+                    "performance_standby" : false,
+                    "sealed" : false,
+                    "server_time_utc" : 1_700_000_000,
+                    "standby" : false,
+                    "version" : "1.15.0",
+                }))
+            }),
+        ))
+        .await;
+
+        let (status, body) = send(app(checking_vault(vault)), ready()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "status" : "UP", "checks" : { "vault" : { "status" : "UP" } } })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_when_vault_is_unreachable() {
+        let state = checking_vault("http://127.0.0.1:1".into());
+
+        let (status, body) = send(app(state), ready()).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "DOWN");
+        assert_eq!(body["checks"]["vault"]["status"], "DOWN");
+        assert!(
+            body["checks"]["vault"]["reason"]
+                .as_str()
+                .unwrap()
+                .starts_with("Vault health check failed"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_each_check() {
+        let state = with_checks(
+            AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new())),
+            vec![
+                Box::new(FakeCheck::new("vault", None)),
+                Box::new(FakeCheck::new("database", Some("connection refused"))),
+            ],
+        );
+
+        let (status, body) = send(app(state), ready()).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({
+                "status" : "DOWN",
+                "checks" : {
+                    "vault" : { "status" : "UP" },
+                    "database" : { "status" : "DOWN", "reason" : "connection refused" },
+                },
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_probes_run_the_checks_once_per_ttl() {
+        let check = FakeCheck::new("vault", None);
+        let runs = check.runs.clone();
+        let readiness = Readiness::new(vec![Box::new(check)], Duration::from_secs(2));
+
+        futures::future::join_all((0..50).map(|_| readiness.status())).await;
+        for _ in 0..50 {
+            readiness.status().await;
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(Duration::from_millis(1999)).await;
+        readiness.status().await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        futures::future::join_all((0..50).map(|_| readiness.status())).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn not_ready_once_shutdown_is_requested() {
+        let state = AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()));
+        let (status, _) = send(app(state.clone()), ready()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let shutdown = Request::post("/admin/shutdown")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(app(state.clone()), shutdown).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, body) = send(app(state), ready()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({ "status" : "DOWN", "reason" : "shutting down" })
+        );
+    }
+
+    #[tokio::test]
+    async fn probes_are_served_at_the_configured_paths() {
+        let settings = crate::Settings {
+            health_path: "/livez".into(),
+            ready_path: "/readyz".into(),
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ));
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        assert_eq!(send(router.clone(), get("/livez")).await.0, StatusCode::OK);
+        assert_eq!(send(router.clone(), get("/readyz")).await.0, StatusCode::OK);
+        assert_eq!(
+            send(router.clone(), get("/health")).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(send(router, get("/ready")).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn logs_each_transition_once() {
+        let check = FakeCheck::new("database", Some("connection refused"));
+        let down = check.down.clone();
+        let readiness = Readiness::new(vec![Box::new(check)], Duration::ZERO);
+        let buffer = crate::Buffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let buffer = buffer.clone();
+                move || buffer.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        for _ in 0..3 {
+            readiness.status().await;
+        }
+        *down.lock().unwrap() = None;
+        readiness.status().await;
+        readiness.status().await;
+
+        let logged = buffer.contents();
+        assert_eq!(
+            logged.matches("readiness check is DOWN").count(),
+            1,
+            "{}",
+            logged
+        );
+        assert_eq!(
+            logged.matches("readiness check is UP").count(),
+            1,
+            "{}",
+            logged
+        );
+        assert!(logged.find("DOWN") < logged.find("is UP"), "{}", logged);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::settings::JwtAuth;
+use crate::shutdown;
+use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Claims of a validated token, available to handlers as an `Extension`.
+#[derive(Debug, Clone)]
+pub struct Claims(pub Value);
+
+pub enum Rejection {
+    /// Malformed, unsigned by a known key, expired or from the wrong issuer.
+    Invalid(String),
+    /// Valid, but not issued for spot.
+    WrongAudience,
+}
+
+/// The signing keys published at `jwks_url`, refreshed in the background.
+pub struct Jwks {
+    config: JwtAuth,
+    keys: ArcSwap<JwkSet>,
+    http: reqwest::Client,
+}
+
+impl Jwks {
+    pub async fn fetch(config: &JwtAuth) -> anyhow::Result<Jwks> {
+        let http = reqwest::Client::new();
+        let keys = fetch_keys(&http, &config.jwks_url).await?;
+        Ok(Jwks {
+            config: config.clone(),
+            keys: ArcSwap::from_pointee(keys),
+            http,
+        })
+    }
+
+    pub fn validate(&self, token: &str) -> Result<Claims, Rejection> {
+        let invalid = |e: jsonwebtoken::errors::Error| Rejection::Invalid(e.to_string());
+
+        let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+        let keys = self.keys.load();
+        let jwk = match &header.kid {
+            Some(kid) => keys.find(kid),
+            None => keys.keys.first(),
+        }
+        .ok_or_else(|| Rejection::Invalid("token is not signed by a known key".into()))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(invalid)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        match jsonwebtoken::decode::<Value>(token, &key, &validation) {
+            Ok(data) => Ok(Claims(data.claims)),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidAudience) => {
+                Err(Rejection::WrongAudience)
+            }
+            Err(e) => Err(invalid(e)),
+        }
+    }
+
+    /// Re-fetches the key set every `refresh_interval_secs`, keeping the old keys on failure.
+    pub async fn refresh(self: Arc<Self>, shutdown: CancellationToken) {
+        let interval = Duration::from_secs(self.config.refresh_interval_secs);
+        while shutdown::sleep(&shutdown, interval).await {
+            match fetch_keys(&self.http, &self.config.jwks_url).await {
+                Ok(keys) => {
+                    tracing::debug!("refreshed {} JWKS keys", keys.keys.len());
+                    self.keys.store(Arc::new(keys));
+                }
+                Err(e) => tracing::error!("failed to refresh JWKS, keeping previous keys: {:#}", e),
+            }
+        }
+    }
+}
+
+async fn fetch_keys(http: &reqwest::Client, url: &str) -> anyhow::Result<JwkSet> {
+    let keys: JwkSet = http
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("failed to fetch JWKS from {}", url))?
+        .json()
+        .await
+        .with_context(|| format!("invalid JWKS at {}", url))?;
+    if keys.keys.is_empty() {
+        return Err(anyhow!("JWKS at {} has no keys", url));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"a-signing-secret-for-tests";
+    /// `SECRET` in unpadded base64url, as a JWK carries it.
+    const SECRET_BASE64: &str = "YS1zaWduaW5nLXNlY3JldC1mb3ItdGVzdHM";
+
+    fn jwks() -> Jwks {
+        let keys = json!({ "keys" : [{
+            "kty" : "oct",
+            "kid" : "test",
+            "alg" : "HS256",
+            "k" : SECRET_BASE64,
+        }] });
+        Jwks {
+            config: JwtAuth {
+                jwks_url: "http://127.0.0.1:1/jwks.json".into(),
+                issuer: "https://issuer.example".into(),
+                audience: "spot".into(),
+                refresh_interval_secs: 300,
+            },
+            keys: ArcSwap::from_pointee(serde_json::from_value(keys).unwrap()),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn token(claims: Value, secret: &[u8]) -> String {
+        let header = Header {
+            kid: Some("test".into()),
+            ..Header::default()
+        };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn claims(audience: &str) -> Value {
+        json!({
+            "sub" : "billing",
+            "iss" : "https://issuer.example",
+            "aud" : audience,
+            "exp" : jsonwebtoken::get_current_timestamp() + 60,
+        })
+    }
+
+    #[test]
+    fn accepts_a_token_signed_by_a_published_key() {
+        let Ok(Claims(claims)) = jwks().validate(&token(claims("spot"), SECRET)) else {
+            panic!("token rejected");
+        };
+        assert_eq!(claims["sub"], "billing");
+    }
+
+    #[test]
+    fn rejects_a_token_signed_by_another_key() {
+        let validated = jwks().validate(&token(claims("spot"), b"some-other-secret"));
+        assert!(matches!(validated, Err(Rejection::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let mut claims = claims("spot");
+        claims["exp"] = json!(jsonwebtoken::get_current_timestamp() - 3600);
+        let validated = jwks().validate(&token(claims, SECRET));
+        assert!(matches!(validated, Err(Rejection::Invalid(_))));
+    }
+
+    #[test]
+    fn tells_a_wrong_audience_apart() {
+        let validated = jwks().validate(&token(claims("billing"), SECRET));
+        assert!(matches!(validated, Err(Rejection::WrongAudience)));
+    }
+}
@@ -1,92 +1,1037 @@
+mod admin;
+mod audit;
+mod auth;
+mod breaker;
+mod cache;
+mod client_ip;
+mod coalesce;
+mod cors;
+mod diagnostics;
+mod error;
+mod health;
+mod jwt;
+mod metrics;
+mod mtls;
+mod openapi;
+mod profile;
+mod rate_limit;
+mod reload;
+mod render;
+mod routes;
+mod secrets;
+mod server;
+mod settings;
+mod shutdown;
+mod store;
+mod telemetry;
+mod timing;
+mod transit;
+mod vault;
+
 use anyhow::anyhow;
-use axum::{response::IntoResponse, routing::get, Router};
-use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
-use config::Config;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, OriginalUri, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    BoxError, Extension, Router,
+};
+use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
+use clap::Parser;
+use error::{error_response, AppError};
+use metrics_exporter_prometheus::PrometheusHandle;
+use regex::Regex;
+use routes::Routes;
 use serde_json::json;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use settings::Settings;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::CompressionLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+use utoipa_swagger_ui::SwaggerUi;
+use vaultrs::client::VaultClient;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Validate the configuration, print it with credentials redacted and exit
+    #[arg(long)]
+    check_config: bool,
+
+    /// Configuration file to load instead of ./spot.{toml,yaml,json}
+    #[arg(long, env = "SPOT_CONFIG_FILE")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    settings: Arc<ArcSwap<Settings>>,
+    store: Arc<dyn store::SecretStore>,
+    /// The client behind `store`, for authentication and health checks.
+    vault: Arc<ArcSwap<VaultClient>>,
+    vault_connected: Arc<AtomicBool>,
+    renewer: Arc<vault::Renewer>,
+    metrics: Option<PrometheusHandle>,
+    rate_limiter: Arc<ArcSwapOption<rate_limit::Limiter>>,
+    cache: Option<cache::SecretCache>,
+    /// Secret reads in progress, shared by concurrent requests for the same secret.
+    reads: Arc<secrets::Reads>,
+    /// `secret_path_pattern`, compiled.
+    path_pattern: Regex,
+    audit: Arc<audit::AuditLog>,
+    jwks: Option<Arc<jwt::Jwks>>,
+    log_filter: telemetry::LogFilterHandle,
+    readiness: Arc<health::Readiness>,
+    /// Requests currently being handled, waited on during shutdown.
+    requests: TaskTracker,
+    /// Cancelled by a shutdown signal or `POST /admin/shutdown`.
+    shutdown: CancellationToken,
+}
 
-async fn health() -> impl IntoResponse {
+/// Up whenever the process is serving, or draining once shutdown has begun when
+/// `health_draining_enabled` is set.
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses((status = 200, description = "Alive", body = openapi::Health))
+)]
+async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    if state.shutdown.is_cancelled() && state.settings.load().health_draining_enabled {
+        return axum::Json(json!({ "status" : "draining" }));
+    }
     axum::Json(json!({ "status" : "UP" }))
 }
 
-fn app() -> Router {
-    Router::new()
-        .route("/", get(health))
-        .layer(opentelemetry_tracing_layer())
-        .layer(TraceLayer::new_for_http())
-        .route("/health", get(health))
+async fn version() -> impl IntoResponse {
+    axum::Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("SPOT_GIT_SHA"),
+        "build_timestamp": env!("SPOT_BUILD_TIMESTAMP"),
+    }))
+}
+
+/// Reads the limit per request so that it can be changed by a reload, and passes the
+/// deadline on to the Vault calls made for the request.
+async fn timeout<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let limit = Duration::from_secs(state.settings.load().request_timeout_secs);
+    let deadline = tokio::time::Instant::now() + limit;
+    match tokio::time::timeout_at(deadline, vault::DEADLINE.scope(deadline, next.run(req))).await {
+        Ok(response) => response,
+        Err(_) => error_response(StatusCode::GATEWAY_TIMEOUT, "timeout", "request timed out"),
+    }
+}
+
+async fn handle_middleware_error(error: BoxError) -> Response {
+    if error.is::<tower::load_shed::error::Overloaded>() {
+        error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "too many requests in flight, try again later",
+        )
+    } else {
+        AppError::Internal(anyhow!("unhandled internal error: {}", error)).into_response()
+    }
+}
+
+/// The panic hook installed by [`telemetry::init_tracing`] has already logged the details.
+fn panic_response(_: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "internal server error",
+    )
+}
+
+async fn not_found(OriginalUri(uri): OriginalUri) -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        format!("no route for {}", uri.path()),
+    )
+}
+
+/// Gives the empty 405s of routes without a handler for the method the JSON error body.
+async fn method_not_allowed(response: Response) -> Response {
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED
+        || response.headers().contains_key(header::CONTENT_TYPE)
+    {
+        return response;
+    }
+    let (mut parts, _) = response.into_parts();
+    let mut json = error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "method not allowed",
+    );
+    // Keep Allow, listing the methods the route does have, and the request id
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(json.headers_mut().drain());
+    Response::from_parts(parts, json.into_body())
 }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
+/// Tags every log line emitted while handling a request with its `X-Request-Id`.
+fn request_span<B>(req: &Request<B>) -> tracing::Span {
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let client_ip = req
+        .extensions()
+        .get::<client_ip::ClientIp>()
+        .map(|client_ip::ClientIp(ip)| ip.to_string())
+        .unwrap_or_default();
+    tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id,
+        client_ip,
+    )
+}
+
+fn app(state: AppState) -> Router {
+    let mut routes =
+        Routes::new(Router::new().fallback(not_found)).route("/", &[Method::GET], get(health));
+    if state.settings.load().debug_endpoints {
+        routes = routes.route("/debug/profile", &[Method::GET], get(profile::cpu));
+    }
+    let mut secret = get(secrets::read);
+    let mut secret_methods = vec![Method::GET];
+    if state.settings.load().read_only {
+        // Without the write handlers, PUT and DELETE get the same 404 as unknown paths
+        secret = secret.fallback(|| async {
+            error_response(StatusCode::NOT_FOUND, "not_found", "spot is read-only")
+        });
+    } else {
+        secret = secret.put(secrets::write).delete(secrets::delete);
+        secret_methods.extend([Method::PUT, Method::DELETE]);
+    }
+    routes = routes
+        .route(
+            "/secret/*path",
+            &secret_methods,
+            secret
+                .layer(DefaultBodyLimit::max(
+                    state.settings.load().max_secret_bytes,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_vault,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_allowed_mount,
+                )),
+        )
+        .route(
+            "/secrets/batch",
+            &[Method::POST],
+            post(secrets::batch)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_vault,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_allowed_mount,
+                )),
+        )
+        .route(
+            "/render",
+            &[Method::POST],
+            post(secrets::render)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_vault,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    secrets::require_allowed_mount,
+                )),
+        )
+        .route(
+            "/admin/cache/invalidate",
+            &[Method::POST],
+            post(admin::invalidate_cache),
+        )
+        .route("/admin/config", &[Method::GET], get(admin::config))
+        .route(
+            "/admin/log-level",
+            &[Method::PUT],
+            put(admin::set_log_level),
+        )
+        .route("/admin/routes", &[Method::GET], get(admin::routes))
+        .route(
+            "/admin/secrets/health",
+            &[Method::GET],
+            get(admin::secrets_health),
+        )
+        .route(
+            "/admin/vault/renew",
+            &[Method::POST],
+            post(admin::renew_vault_token),
+        )
+        .route(
+            "/admin/vault/status",
+            &[Method::GET],
+            get(admin::vault_status),
+        )
+        .route("/admin/shutdown", &[Method::POST], post(admin::shutdown))
+        .map(|router| {
+            router
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit::limit,
+                ))
+                .route_layer(middleware::from_fn(metrics::track))
+        })
+        // Probes and the routes describing spot skip the rate limiter and http_requests_total,
+        // but are traced like the rest
+        .route(
+            &state.settings.load().health_path,
+            &[Method::GET],
+            get(health),
+        )
+        .route(
+            &state.settings.load().ready_path,
+            &[Method::GET],
+            get(health::ready),
+        )
+        .route("/version", &[Method::GET], get(version))
+        .route("/openapi.json", &[Method::GET], get(openapi::spec));
+    if state.settings.load().swagger_ui_enabled {
+        let base_path = state.settings.load().base_path.clone();
+        let swagger_ui: Router<AppState> = SwaggerUi::new("/swagger-ui")
+            .config(utoipa_swagger_ui::Config::new([format!(
+                "{}/openapi.json",
+                base_path
+            )]))
+            .into();
+        // The UI redirects /swagger-ui to /swagger-ui/ without knowing it is nested
+        routes = routes.merge(
+            "/swagger-ui/*rest",
+            &[Method::GET],
+            swagger_ui.layer(middleware::map_response(move |mut response: Response| {
+                let base_path = base_path.clone();
+                async move {
+                    if let Some(location) = response.headers().get(header::LOCATION) {
+                        let location = format!("{}{}", base_path, location.to_str().unwrap_or(""));
+                        if let Ok(location) = HeaderValue::from_str(&location) {
+                            response.headers_mut().insert(header::LOCATION, location);
+                        }
+                    }
+                    response
+                }
+            })),
+        );
+    }
+    if state.settings.load().metrics_enabled {
+        routes = routes.route("/metrics", &[Method::GET], get(metrics::render));
+    }
+    routes = routes.map(|router| {
+        router
+            .layer(CatchPanicLayer::custom(panic_response))
+            .layer(OtelAxumLayer::default())
+            .layer(TraceLayer::new_for_http().make_span_with(request_span))
+    });
+    let base_path = state.settings.load().base_path.clone();
+    let (mut router, registered) = routes.finish(&base_path);
+    // Router::layer applies per route, so share one semaphore to bound the whole server
+    let inflight = Arc::new(Semaphore::new(state.settings.load().max_inflight));
+    router = router
+        // Routes with their own DefaultBodyLimit keep it
+        .layer(DefaultBodyLimit::max(state.settings.load().max_body_bytes))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(handle_middleware_error))
+                .load_shed()
+                .layer(GlobalConcurrencyLimitLayer::with_semaphore(inflight))
+                .layer(middleware::from_fn_with_state(state.clone(), timeout)),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token,
+        ));
+    // Outside the timeout so that the deadline covers producing the response, not sending it
+    if state.settings.load().compression_enabled {
+        router = router.layer(CompressionLayer::new());
+    }
+    // Outside auth so that the token check is part of the breakdown
+    if state.settings.load().timing_header_enabled {
+        router = router.layer(middleware::from_fn(timing::report));
+    }
+    router = router
+        // Outside auth and the middleware error handler so that rejections carry the id too
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shutdown::track_request,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            client_ip::resolve,
+        ))
+        .layer(middleware::map_response(method_not_allowed));
+    // Preflight requests carry no credentials, so answer them before authentication
+    if let Some(cors) = &state.settings.load().cors {
+        router = router.layer(cors::layer(cors).expect("validated by Settings::new"));
+    }
+    if !base_path.is_empty() {
+        router = Router::new().nest(&base_path, router).fallback(not_found);
+    }
+    router
+        .layer(Extension(Arc::new(registered)))
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let settings = Settings::new(cli.config.as_deref())?;
+    if cli.check_config {
+        vault::client(&settings)?;
+        println!("{:#}", settings.redacted());
+        return Ok(());
+    }
+    let settings = transit::decrypt(settings).await?;
+
+    let log_filter = telemetry::init_tracing(&settings)?;
+    let meter_provider = telemetry::init_metrics(&settings)?;
+
+    tracing::info!(settings = %settings.redacted(), "effective configuration");
+    if settings.read_only {
+        tracing::warn!("read-only mode: secret writes and deletes are disabled");
+    }
+    if !settings.mount_allowed(&settings.vault_mount) {
+        tracing::warn!(
+            "vault_mount {} is not an allowed mount, secret requests without X-Vault-Mount will get 403",
+            settings.vault_mount
+        );
+    }
+
+    let metrics = metrics::init(&settings)?;
+
+    let (client, lease) = match vault::connect_with_retry(&settings).await {
+        Ok((client, lease)) => (client, Some(lease)),
+        Err(e) if settings.vault_fail_open => {
+            tracing::error!("starting without Vault, secret routes will fail: {:#}", e);
+            (vault::client(&settings)?, None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // Installed only now so that the connection attempts above are not cut short
+    if let Some(config) = &settings.circuit_breaker {
+        breaker::install(config);
+    }
+    let vault_connected = Arc::new(AtomicBool::new(lease.is_some()));
+    let jwks = match settings.jwt.as_ref().filter(|_| settings.auth_enabled) {
+        Some(config) => Some(Arc::new(jwt::Jwks::fetch(config).await?)),
+        None => None,
     };
 
-    let terminate = async {
-        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
+    let vault = Arc::new(ArcSwap::from_pointee(client));
+    let readiness = health::Readiness::new(
+        vec![Box::new(health::VaultCheck {
+            vault: vault.clone(),
+            connected: vault_connected.clone(),
+        })],
+        Duration::from_secs(settings.readiness_cache_secs),
+    );
+    let rate_limiter = rate_limit::limiters(settings.rate_limit.as_ref());
+    let cache = settings.cache_enabled.then(|| cache::new(&settings));
+    let audit = Arc::new(audit::AuditLog::open(&settings.audit)?);
+    let settings = Arc::new(ArcSwap::from_pointee(settings));
+    let shutdown = CancellationToken::new();
+    let state = AppState {
+        store: store::for_settings(vault.clone(), settings.clone()),
+        vault,
+        vault_connected,
+        renewer: Arc::new(vault::Renewer::new(lease)),
+        rate_limiter,
+        jwks,
+        cache,
+        reads: Arc::new(coalesce::SingleFlight::new()),
+        path_pattern: Regex::new(&settings.load().secret_path_pattern)
+            .expect("validated by Settings::new"),
+        audit,
+        settings,
+        metrics,
+        log_filter,
+        readiness: Arc::new(readiness),
+        requests: TaskTracker::new(),
+        shutdown: shutdown.clone(),
     };
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+    let required = &state.settings.load().required_secrets;
+    if lease.is_some() {
+        store::require(&*state.store, &state.settings.load().vault_mount, required).await?;
+    } else if !required.is_empty() {
+        tracing::warn!("not connected to Vault, skipping the required_secrets check");
     }
 
-    tracing::warn!("signal received, starting graceful shutdown");
+    let settings = state.settings.load_full();
+    match &state.cache {
+        _ if settings.preload_paths.is_empty() => {}
+        Some(cache) if lease.is_some() => {
+            let preloaded = cache::preload(
+                cache,
+                &*state.store,
+                &settings.vault_mount,
+                &settings.preload_paths,
+                settings.batch_concurrency,
+            )
+            .await;
+            match preloaded {
+                Err(e) if !settings.preload_required => tracing::warn!("{:#}", e),
+                result => result?,
+            }
+        }
+        Some(_) => tracing::warn!("not connected to Vault, skipping the cache preload"),
+        None => tracing::warn!("cache_enabled is off, ignoring preload_paths"),
+    }
+
+    let tasks = TaskTracker::new();
+    if lease.is_some() {
+        tasks.spawn(vault::renew_token(
+            state.vault.clone(),
+            state.settings.load_full(),
+            state.renewer.clone(),
+            shutdown.clone(),
+        ));
+    } else {
+        tasks.spawn(vault::reconnect(
+            state.vault.clone(),
+            state.settings.clone(),
+            state.renewer.clone(),
+            state.vault_connected.clone(),
+            shutdown.clone(),
+        ));
+    }
+    if let Some(jwks) = &state.jwks {
+        tasks.spawn(jwks.clone().refresh(shutdown.clone()));
+    }
+    tasks.spawn(rate_limit::prune(
+        state.rate_limiter.clone(),
+        shutdown.clone(),
+    ));
+    match settings.runtime_metrics_interval_secs {
+        Some(secs) if state.metrics.is_some() => {
+            tasks.spawn(metrics::sample_runtime(
+                Duration::from_secs(secs),
+                shutdown.clone(),
+            ));
+        }
+        Some(_) => tracing::warn!("no metrics recorder, ignoring runtime_metrics_interval_secs"),
+        None => {}
+    }
+    tasks.spawn(reload::on_sighup(
+        state.clone(),
+        cli.config,
+        shutdown.clone(),
+    ));
+    tasks.spawn(reload::on_sigusr1(
+        state.settings.clone(),
+        state.log_filter.clone(),
+        shutdown.clone(),
+    ));
+    tasks.spawn(diagnostics::on_sigquit(
+        state.clone(),
+        tasks.clone(),
+        shutdown.clone(),
+    ));
+    tokio::spawn(shutdown::signal(
+        settings.shutdown_signals.clone(),
+        shutdown.clone(),
+    ));
+
+    let settings = state.settings.clone();
+    let requests = state.requests.clone();
+    server::serve(app(state), &settings.load(), requests, shutdown.clone()).await?;
+
+    shutdown.cancel();
+    let grace = Duration::from_secs(settings.load().shutdown_grace_period_secs);
+    shutdown::drain(tasks, grace).await;
     opentelemetry::global::shutdown_tracer_provider();
+    if let Some(provider) = meter_provider {
+        provider.shutdown()?;
+    }
+
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    opentelemetry::global::set_error_handler(|error| {
-            ::tracing::error!(target: "opentelemetry", "OpenTelemetry error occurred: {:#}", anyhow!(error));
+/// A state around `store` whose client talks to the Vault at `vault_address`, without
+/// connecting yet.
+#[cfg(test)]
+impl AppState {
+    fn for_tests(settings: Settings, store: Arc<dyn store::SecretStore>) -> Self {
+        use tracing_subscriber::{reload, EnvFilter, Registry};
+
+        let (filter, log_filter) =
+            reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new(&settings.log_filter));
+        // The handle only works while the layer is alive, and there is no subscriber to own it
+        std::mem::forget(filter);
+        AppState {
+            store,
+            vault: Arc::new(ArcSwap::from_pointee(
+                vault::client(&settings).expect("valid vault_address"),
+            )),
+            metrics: None,
+            rate_limiter: rate_limit::limiters(settings.rate_limit.as_ref()),
+            log_filter,
+            cache: settings.cache_enabled.then(|| cache::new(&settings)),
+            audit: Arc::new(audit::AuditLog::new(Box::new(std::io::sink()))),
+            jwks: None,
+            readiness: Arc::new(health::Readiness::new(vec![], Duration::ZERO)),
+            vault_connected: Arc::new(AtomicBool::new(true)),
+            renewer: Arc::new(vault::Renewer::new(None)),
+            requests: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            reads: Arc::new(coalesce::SingleFlight::new()),
+            path_pattern: Regex::new(&settings.secret_path_pattern).expect("valid pattern"),
+            settings: Arc::new(ArcSwap::from_pointee(settings)),
+        }
+    }
+
+    /// A state whose secrets come from the Vault at `vault_address`, as in production.
+    fn against_vault(settings: Settings) -> Self {
+        let state = AppState::for_tests(settings, Arc::new(store::MockSecretStore::new()));
+        AppState {
+            store: store::for_settings(state.vault.clone(), state.settings.clone()),
+            ..state
+        }
+    }
+}
+
+/// Serves `routes` on a local port in place of Vault, returning the address to set as
+/// `vault_address`.
+#[cfg(test)]
+async fn fake_vault(routes: Router) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("a free local port");
+    let address = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener).expect("listener is usable");
+    tokio::spawn(server.serve(routes.into_make_service()));
+    address
+}
+
+/// Collects what is written to it, such as the lines of a test's log subscriber.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Buffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).expect("written as UTF-8")
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Vault's response envelope around `data`.
+#[cfg(test)]
+fn vault_response(data: serde_json::Value) -> axum::Json<serde_json::Value> {
+    axum::Json(json!({
+        "request_id" : "6f1a4a3c-0d0c-4e5f-9a0e-0e4b7d1c2a10",
+        "lease_id" : "",
+        "lease_duration" : 0,
+        "renewable" : false,
+        "data" : data,
+        "warnings" : null,
+        "wrap_info" : null,
+        "auth" : null,
+    }))
+}
+
+/// Settings for router tests, without authentication.
+#[cfg(test)]
+fn test_settings() -> Settings {
+    Settings {
+        auth_enabled: false,
+        ..Settings::default()
+    }
+}
+
+/// Sends `request` through `router` and returns the status and parsed JSON body, which is
+/// `null` when empty or not JSON.
+#[cfg(test)]
+async fn send(
+    router: Router,
+    request: axum::http::Request<axum::body::Body>,
+) -> (StatusCode, serde_json::Value) {
+    use tower::ServiceExt;
+
+    let response = router.oneshot(request).await.expect("router is infallible");
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .expect("body is readable");
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::header, http::Request};
+    use store::MockSecretStore;
+
+    fn state(vault_address: &str) -> AppState {
+        AppState::against_vault(Settings {
+            vault_address: vault_address.into(),
+            ..test_settings()
         })
-        .expect("to be able to set error handler");
+    }
 
-    let _tracer =
-        opentelemetry_datadog::new_pipeline().install_batch(opentelemetry::runtime::Tokio)?;
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| "opentelemetry=debug,spot=debug,tower_http=debug".into()),
+    #[tokio::test]
+    async fn router_serves_health_with_a_dummy_state() {
+        let (status, body) = send(app(state("http://127.0.0.1:1")), get("/health")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "status" : "UP" }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_request_times_out() {
+        let vault = fake_vault(Router::new().route(
+            "/v1/secret/data/app/db",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                StatusCode::NOT_FOUND
+            }),
         ))
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
-
-    let settings = Config::builder()
-        // Add in `./Settings.toml`
-        .add_source(config::File::with_name("spot"))
-        .add_source(config::Environment::with_prefix("SPOT"))
-        .build()?;
-
-    tracing::info!(
-        "settings {:?}",
-        settings.try_deserialize::<HashMap<String, String>>()?
-    );
+        .await;
+        let settings = Settings {
+            vault_address: vault,
+            request_timeout_secs: 1,
+            ..test_settings()
+        };
 
-    let _client = VaultClient::new(
-        VaultClientSettingsBuilder::default()
-            .address("https://127.0.0.1:8200")
-            .token("TOKEN")
-            .build()?,
-    )?;
-
-    let app = app();
-    let addr = &"0.0.0.0:3000".parse::<SocketAddr>()?;
-    tracing::warn!("listening on {}", addr);
-    axum::Server::bind(addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+        let (status, body) = send(
+            app(AppState::against_vault(settings)),
+            get("/secret/app/db"),
+        )
+        .await;
 
-    Ok(())
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body["error"]["code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_inflight_limit_are_shed() {
+        let vault = fake_vault(Router::new().route(
+            "/v1/secret/data/app/db",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                (StatusCode::NOT_FOUND, axum::Json(json!({ "errors" : [] })))
+            }),
+        ))
+        .await;
+        let settings = Settings {
+            vault_address: vault,
+            max_inflight: 2,
+            ..test_settings()
+        };
+        let router = app(AppState::against_vault(settings));
+
+        let requests: Vec<_> = (0..5)
+            .map(|_| tokio::spawn(send(router.clone(), get("/secret/app/db"))))
+            .collect();
+        let mut statuses = Vec::new();
+        for request in requests {
+            statuses.push(request.await.unwrap().0);
+        }
+
+        let served = statuses
+            .iter()
+            .filter(|s| **s == StatusCode::NOT_FOUND)
+            .count();
+        let shed = statuses
+            .iter()
+            .filter(|s| **s == StatusCode::SERVICE_UNAVAILABLE)
+            .count();
+        assert_eq!((served, shed), (2, 3), "{:?}", statuses);
+    }
+
+    #[tokio::test]
+    async fn version_is_the_package_version() {
+        let manifest = include_str!("../Cargo.toml");
+        let package_version = manifest
+            .lines()
+            .find_map(|line| line.strip_prefix("version = "))
+            .map(|version| version.trim_matches('"'))
+            .unwrap();
+
+        let (status, body) = send(
+            app(AppState::for_tests(
+                test_settings(),
+                Arc::new(MockSecretStore::new()),
+            )),
+            get("/version"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], package_version);
+        assert!(body["git_sha"].as_str().is_some_and(|sha| !sha.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_request_id() {
+        use tower::ServiceExt;
+
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+        let request_id = |response: &Response| response.headers()["x-request-id"].clone();
+
+        let generated = router.clone().oneshot(get("/health")).await.unwrap();
+        assert!(!request_id(&generated).is_empty());
+
+        let request = Request::get("/health")
+            .header("x-request-id", "billing-7f3c")
+            .body(Body::empty())
+            .unwrap();
+        let preserved = router.oneshot(request).await.unwrap();
+        assert_eq!(request_id(&preserved), "billing-7f3c");
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_gzipped_when_accepted() {
+        use tower::ServiceExt;
+
+        let certificate = "MIIC".repeat(2048);
+        let vault = fake_vault(Router::new().route(
+            "/v1/secret/data/app/tls",
+            axum::routing::get({
+                let certificate = certificate.clone();
+                move || async move {
+                    vault_response(json!({
+                        "data" : { "cert" : certificate },
+                        "metadata" : {
+                            "created_time" : "2024-05-01T09:30:00Z",
+                            "deletion_time" : "",
+                            "destroyed" : false,
+                            "version" : 1,
+                        },
+                    }))
+                }
+            }),
+        ))
+        .await;
+        let router = app(state(&vault));
+        let request = Request::get("/secret/app/tls")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(compressed.len() < certificate.len() / 10);
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_global_limit_are_rejected() {
+        let vault = fake_vault(Router::new().route(
+            "/v1/secret/data/app/db",
+            axum::routing::get(|| async {
+                vault_response(json!({
+                    "data" : { "password" : "hunter2" },
+                    "metadata" : {
+                        "created_time" : "2024-05-01T09:30:00Z",
+                        "deletion_time" : "",
+                        "destroyed" : false,
+                        "version" : 1,
+                    },
+                }))
+            }),
+        ))
+        .await;
+        let body = json!({ "paths" : ["app/db"] }).to_string();
+        let settings = Settings {
+            vault_address: vault,
+            max_body_bytes: body.len() + 1,
+            ..test_settings()
+        };
+        let router = app(AppState::against_vault(settings));
+        let batch = |body: String| {
+            Request::post("/secrets/batch")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let (status, _) = send(router.clone(), batch(format!("{} ", body))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, response) = send(router, batch(format!("{}  ", body))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response["error"]["code"], "payload_too_large");
+        assert_eq!(
+            response["error"]["message"],
+            format!("request body exceeds the limit of {} bytes", body.len() + 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_a_secret_from_the_store() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        let router = app(AppState::for_tests(test_settings(), store.clone()));
+
+        let (status, body) = send(router, get("/secret/app/db")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+        assert_eq!(store.reads(), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_secret_is_not_found() {
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+
+        let (status, body) = send(router, get("/secret/app/missing")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn panicking_handler_is_an_internal_error_and_the_server_keeps_serving() {
+        let store = MockSecretStore::new()
+            .with("app/db", json!({ "password" : "hunter2" }))
+            .panicking("app/bug");
+        let router = app(AppState::for_tests(test_settings(), Arc::new(store)));
+
+        let (status, body) = send(router.clone(), get("/secret/app/bug")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["code"], "internal_error");
+
+        let (status, body) = send(router, get("/secret/app/db")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["password"], "hunter2");
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_serves_reads_and_refuses_writes() {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let settings = Settings {
+            read_only: true,
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, Arc::new(store)));
+
+        let (status, _) = send(router.clone(), get("/secret/app/db")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let write = Request::put("/secret/app/db")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "password" : "changed" }).to_string()))
+            .unwrap();
+        let delete = Request::delete("/secret/app/db")
+            .body(Body::empty())
+            .unwrap();
+        for request in [write, delete] {
+            let (status, body) = send(router.clone(), request).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body["error"]["code"], "not_found");
+            assert_eq!(body["error"]["message"], "spot is read-only");
+        }
+
+        let (_, body) = send(router, get("/secret/app/db")).await;
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn routes_are_served_below_the_base_path() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        let settings = Settings {
+            base_path: "/spot".into(),
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, store));
+
+        assert_eq!(
+            send(router.clone(), get("/spot/health")).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(router.clone(), get("/spot/secret/app/db")).await.0,
+            StatusCode::OK
+        );
+        let (status, body) = send(router, get("/health")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_a_json_not_found() {
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+
+        let (status, body) = send(router, get("/no/such/route")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("/no/such/route"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_is_a_json_method_not_allowed() {
+        use tower::ServiceExt;
+
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+        let request = Request::post("/health").body(Body::empty()).unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response.headers().contains_key(header::ALLOW));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+    }
 }
@@ -0,0 +1,255 @@
+use axum::{
+    extract::{MatchedPath, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::error_response;
+use crate::settings::Settings;
+use crate::{shutdown, AppState};
+
+const REQUEST_DURATION_SECONDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("http_requests_duration_seconds".into()),
+            REQUEST_DURATION_SECONDS,
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("vault_operation_duration_seconds".into()),
+            REQUEST_DURATION_SECONDS,
+        )?
+        .install_recorder()?;
+    Ok(handle)
+}
+
+/// Records the count and latency of requests to each matched route.
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    ::metrics::increment_counter!("http_requests_total", &labels);
+    ::metrics::histogram!(
+        "http_requests_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        &labels
+    );
+
+    response
+}
+
+/// Answers 503 when the recorder could not be installed at startup.
+pub async fn render(State(state): State<AppState>) -> Response {
+    match &state.metrics {
+        Some(handle) => handle.render().into_response(),
+        None => error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "metrics_unavailable",
+            "the metrics recorder failed to initialize",
+        ),
+    }
+}
+
+/// The recorder when `metrics_enabled`; if it cannot be installed and `metrics_required` is
+/// false, none, so that spot still starts and `/metrics` answers 503.
+pub fn init(settings: &Settings) -> anyhow::Result<Option<PrometheusHandle>> {
+    match settings.metrics_enabled.then(install) {
+        Some(Ok(handle)) => Ok(Some(handle)),
+        Some(Err(e)) if !settings.metrics_required => {
+            tracing::warn!("serving without metrics, /metrics will return 503: {:#}", e);
+            Ok(None)
+        }
+        Some(Err(e)) => Err(e.context("failed to install metrics recorder")),
+        None => Ok(None),
+    }
+}
+
+/// Publishes the Tokio runtime's counters as `tokio_*` gauges every `interval`.
+///
+/// The per-worker queue depths and the blocking pool are only sampled in builds with
+/// `--cfg tokio_unstable`, and the busy ratios only where Tokio keeps 64-bit counters.
+pub async fn sample_runtime(interval: Duration, shutdown: CancellationToken) {
+    let runtime = tokio::runtime::Handle::current().metrics();
+    #[cfg(target_has_atomic = "64")]
+    let mut busy = (Instant::now(), busy_durations(&runtime));
+
+    while shutdown::sleep(&shutdown, interval).await {
+        ::metrics::gauge!("tokio_workers", runtime.num_workers() as f64);
+        ::metrics::gauge!("tokio_alive_tasks", runtime.num_alive_tasks() as f64);
+        ::metrics::gauge!(
+            "tokio_global_queue_depth",
+            runtime.global_queue_depth() as f64
+        );
+
+        // The share of the last interval each worker spent running tasks rather than parked
+        #[cfg(target_has_atomic = "64")]
+        {
+            let (now, durations) = (Instant::now(), busy_durations(&runtime));
+            let elapsed = now.duration_since(busy.0).as_secs_f64();
+            for (worker, (total, previous)) in durations.iter().zip(&busy.1).enumerate() {
+                let ratio = total.saturating_sub(*previous).as_secs_f64() / elapsed;
+                ::metrics::gauge!(
+                    "tokio_worker_busy_ratio",
+                    ratio.min(1.0),
+                    "worker" => worker.to_string()
+                );
+            }
+            busy = (now, durations);
+        }
+
+        #[cfg(tokio_unstable)]
+        {
+            for worker in 0..runtime.num_workers() {
+                ::metrics::gauge!(
+                    "tokio_worker_local_queue_depth",
+                    runtime.worker_local_queue_depth(worker) as f64,
+                    "worker" => worker.to_string()
+                );
+            }
+            ::metrics::gauge!(
+                "tokio_blocking_threads",
+                runtime.num_blocking_threads() as f64
+            );
+            ::metrics::gauge!(
+                "tokio_blocking_queue_depth",
+                runtime.blocking_queue_depth() as f64
+            );
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "64")]
+fn busy_durations(runtime: &tokio::runtime::RuntimeMetrics) -> Vec<Duration> {
+    (0..runtime.num_workers())
+        .map(|worker| runtime.worker_total_busy_duration(worker))
+        .collect()
+}
+
+/// The recorder shared by every test, since only one can be installed per process.
+#[cfg(test)]
+pub fn test_handle() -> PrometheusHandle {
+    static HANDLE: std::sync::OnceLock<PrometheusHandle> = std::sync::OnceLock::new();
+    HANDLE
+        .get_or_init(|| install().expect("no other recorder is installed"))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, fake_vault, send, test_settings};
+    use axum::{body::Body, http::StatusCode, routing::get, Json, Router};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_list_requests_and_vault_reads() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/missing",
+            get(|| async { (StatusCode::NOT_FOUND, Json(json!({ "errors" : [] }))) }),
+        );
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            ..test_settings()
+        };
+        let state = AppState {
+            metrics: Some(test_handle()),
+            ..AppState::against_vault(settings)
+        };
+        let router = app(state);
+
+        let (status, _) = send(router.clone(), get_request("/secret/app/missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let response = tower::ServiceExt::oneshot(router, get_request("/metrics"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        for name in [
+            "http_requests_total",
+            "http_requests_duration_seconds_bucket",
+            "vault_reads_total",
+        ] {
+            assert!(body.contains(name), "{} missing from {}", name, body);
+        }
+    }
+
+    #[test]
+    fn recorder_that_fails_to_install_is_left_out() {
+        // A second recorder in the process cannot be installed
+        test_handle();
+        let settings = Settings {
+            metrics_enabled: true,
+            ..test_settings()
+        };
+
+        assert!(init(&settings).unwrap().is_none());
+        assert!(init(&Settings {
+            metrics_required: true,
+            ..settings
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_unavailable_without_a_recorder() {
+        let state = AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()));
+
+        let (status, body) = send(app(state), get_request("/metrics")).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["error"]["code"], "metrics_unavailable");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runtime_sampling_publishes_the_tokio_gauges() {
+        let metrics = test_handle();
+        let shutdown = CancellationToken::new();
+        let sampler = tokio::spawn(sample_runtime(Duration::from_millis(10), shutdown.clone()));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.cancel();
+        sampler.await.unwrap();
+
+        let rendered = metrics.render();
+        for name in [
+            "tokio_workers",
+            "tokio_alive_tasks",
+            "tokio_global_queue_depth",
+            #[cfg(target_has_atomic = "64")]
+            "tokio_worker_busy_ratio",
+        ] {
+            assert!(
+                rendered.contains(name),
+                "{} missing from {}",
+                name,
+                rendered
+            );
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Mutual TLS: requires clients to present a certificate issued by `tls_client_ca_path` and
+//! attaches its subject to their requests.
+
+use anyhow::Context;
+use axum_server::accept::{Accept, DefaultAcceptor};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_http::add_extension::AddExtension;
+
+/// The subject of the verified client certificate of the connection a request came over,
+/// such as `CN=billing, O=Example`.
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub String);
+
+/// A server configuration presenting `cert` and `key` that only completes handshakes with
+/// clients whose certificate chains to one of the CAs in `client_ca`.
+pub fn config(cert: &Path, key: &Path, client_ca: &Path) -> anyhow::Result<RustlsConfig> {
+    let certs = read_pem(cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    let key = read_pem(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {}", key.display()))?;
+
+    let mut roots = RootCertStore::empty();
+    for item in read_pem(client_ca)? {
+        if let Item::X509Certificate(der) = item {
+            roots
+                .add(&Certificate(der))
+                .with_context(|| format!("invalid CA certificate in {}", client_ca.display()))?;
+        }
+    }
+    if roots.is_empty() {
+        anyhow::bail!("no CA certificates in {}", client_ca.display());
+    }
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+fn read_pem(path: &Path) -> anyhow::Result<Vec<Item>> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    rustls_pemfile::read_all(&mut pem.as_slice())
+        .with_context(|| format!("invalid PEM in {}", path.display()))
+}
+
+/// Performs the TLS handshake, then attaches [`ClientCert`] to every request made over the
+/// connection.
+#[derive(Clone)]
+pub struct Acceptor(RustlsAcceptor<DefaultAcceptor>);
+
+impl Acceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Acceptor(RustlsAcceptor::new(config))
+    }
+}
+
+impl<I, S> Accept<I, S> for Acceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCert>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let handshake = self.0.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = handshake.await?;
+            // The verifier has already rejected handshakes without a valid certificate
+            let subject = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|cert| subject(&cert.0))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "unreadable client certificate")
+                })?;
+            Ok((stream, AddExtension::new(service, ClientCert(subject))))
+        })
+    }
+}
+
+fn subject(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    Some(cert.subject().to_string())
+}
@@ -0,0 +1,155 @@
+//! The OpenAPI description of spot's HTTP API, served at `GET /openapi.json`.
+
+use crate::AppState;
+use axum::{extract::State, Json};
+use utoipa::openapi::{path::PathItemType, OpenApi as Spec, Server};
+use utoipa::{OpenApi, ToSchema};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "spot",
+        description = "Reads and writes secrets in Vault over HTTP"
+    ),
+    paths(
+        crate::secrets::read,
+        crate::secrets::write,
+        crate::secrets::delete,
+        crate::secrets::batch,
+        crate::secrets::render,
+        crate::health,
+        crate::health::ready,
+    ),
+    components(schemas(
+        ErrorBody,
+        ErrorDetail,
+        VersionMetadata,
+        crate::secrets::BatchRead,
+        crate::secrets::RenderRequest,
+        BatchResult,
+        Health
+    ))
+)]
+struct ApiDoc;
+
+/// The spec, with the probe routes at their configured paths, `base_path` as the server URL
+/// and without the routes left out in read-only mode.
+pub async fn spec(State(state): State<AppState>) -> Json<Spec> {
+    let settings = state.settings.load();
+    let mut spec = ApiDoc::openapi();
+    for (default, configured) in [
+        ("/health", &settings.health_path),
+        ("/ready", &settings.ready_path),
+    ] {
+        if let Some(item) = spec.paths.paths.remove(default) {
+            spec.paths.paths.insert(configured.clone(), item);
+        }
+    }
+    if !settings.base_path.is_empty() {
+        spec.servers = Some(vec![Server::new(&settings.base_path)]);
+    }
+    if settings.read_only {
+        if let Some(item) = spec.paths.paths.get_mut("/secret/{path}") {
+            item.operations.remove(&PathItemType::Put);
+            item.operations.remove(&PathItemType::Delete);
+        }
+    }
+    Json(spec)
+}
+
+// The types below only describe response bodies that handlers build as JSON values.
+
+/// Returned by every failed request.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct ErrorDetail {
+    /// Stable identifier, e.g. `not_found` or `forbidden`.
+    code: String,
+    message: String,
+    /// The OpenTelemetry trace of the request, when it was traced.
+    trace_id: Option<String>,
+    /// For `missing_secrets`, the paths or template variables that do not exist; for
+    /// `missing_fields`, the requested fields the secret lacks.
+    missing: Option<Vec<String>>,
+}
+
+/// The version created by a write; KV v1 mounts only report a null `version`.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct VersionMetadata {
+    created_time: Option<String>,
+    deletion_time: Option<String>,
+    destroyed: Option<bool>,
+    version: Option<u64>,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct BatchResult {
+    /// Secrets by path.
+    #[schema(value_type = Object)]
+    results: serde_json::Value,
+    /// An [`ErrorDetail`] by path, for every path that could not be read.
+    #[schema(value_type = Object)]
+    errors: serde_json::Value,
+}
+
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct Health {
+    /// `UP` or `DOWN`, or `draining` on the liveness probe with `health_draining_enabled`.
+    status: String,
+    /// Per-check results, for readiness only.
+    #[schema(value_type = Option<Object>)]
+    checks: Option<serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings, Settings};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::Arc;
+
+    async fn served(settings: Settings) -> Spec {
+        let router = app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ));
+        let request = Request::get("/openapi.json").body(Body::empty()).unwrap();
+        let (status, body) = send(router, request).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_value(body).expect("the spec deserializes")
+    }
+
+    #[tokio::test]
+    async fn spec_lists_the_secret_routes() {
+        let spec = served(test_settings()).await;
+
+        let item = &spec.paths.paths["/secret/{path}"];
+        for operation in [PathItemType::Get, PathItemType::Put, PathItemType::Delete] {
+            assert!(item.operations.contains_key(&operation));
+        }
+        assert!(spec.paths.paths.contains_key("/health"));
+    }
+
+    #[tokio::test]
+    async fn read_only_spec_leaves_out_writes() {
+        let spec = served(Settings {
+            read_only: true,
+            ..test_settings()
+        })
+        .await;
+
+        let item = &spec.paths.paths["/secret/{path}"];
+        assert!(item.operations.contains_key(&PathItemType::Get));
+        assert!(!item.operations.contains_key(&PathItemType::Put));
+    }
+}
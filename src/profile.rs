@@ -0,0 +1,114 @@
+use crate::error::AppError;
+use crate::AppState;
+use anyhow::anyhow;
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use pprof::protos::Message;
+use serde::Deserialize;
+use std::time::Duration;
+
+const MAX_PROFILE_SECONDS: u64 = 60;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    #[default]
+    Flamegraph,
+    Pprof,
+}
+
+#[derive(Deserialize)]
+pub struct ProfileParams {
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+fn default_profile_seconds() -> u64 {
+    10
+}
+
+/// Samples the CPU for `seconds` and returns a flamegraph SVG or a pprof protobuf.
+pub async fn cpu(
+    State(state): State<AppState>,
+    Query(params): Query<ProfileParams>,
+) -> Result<Response, AppError> {
+    // Leave headroom so the request timeout does not cut the profile short
+    let limit =
+        MAX_PROFILE_SECONDS.min(state.settings.load().request_timeout_secs.saturating_sub(1));
+    if params.seconds == 0 || params.seconds > limit {
+        return Err(AppError::BadRequest(format!(
+            "seconds must be between 1 and {}",
+            limit
+        )));
+    }
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(99)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| anyhow!("failed to start profiler: {}", e))?;
+    tokio::time::sleep(Duration::from_secs(params.seconds)).await;
+    let report = guard
+        .report()
+        .build()
+        .map_err(|e| anyhow!("failed to build profile: {}", e))?;
+
+    let mut body = Vec::new();
+    let content_type = match params.format {
+        ProfileFormat::Flamegraph => {
+            report
+                .flamegraph(&mut body)
+                .map_err(|e| anyhow!("failed to render flamegraph: {}", e))?;
+            "image/svg+xml"
+        }
+        ProfileFormat::Pprof => {
+            report
+                .pprof()
+                .map_err(|e| anyhow!("failed to encode profile: {}", e))?
+                .encode(&mut body)
+                .map_err(|e| anyhow!("failed to encode profile: {}", e))?;
+            "application/octet-stream"
+        }
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings, Settings};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::Arc;
+
+    async fn profile(debug_endpoints: bool) -> (StatusCode, serde_json::Value) {
+        let settings = Settings {
+            debug_endpoints,
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ));
+        // Out of range, so that an enabled endpoint answers without profiling
+        let request = Request::get("/debug/profile?seconds=0")
+            .body(Body::empty())
+            .unwrap();
+        send(router, request).await
+    }
+
+    #[tokio::test]
+    async fn endpoint_is_not_found_unless_enabled() {
+        let (status, body) = profile(false).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+
+        let (status, _) = profile(true).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
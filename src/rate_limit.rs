@@ -0,0 +1,124 @@
+use crate::client_ip::ClientIp;
+use crate::error::error_response;
+use crate::settings::RateLimit;
+use crate::{shutdown, AppState};
+use arc_swap::ArcSwapOption;
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+pub type Limiter = DefaultKeyedRateLimiter<String>;
+
+pub fn limiter(config: &RateLimit) -> Limiter {
+    RateLimiter::keyed(Quota::per_second(config.per_second).allow_burst(config.burst))
+}
+
+/// The limiter for `rate_limit`, replaced when a reload changes it.
+pub fn limiters(rate_limit: Option<&RateLimit>) -> Arc<ArcSwapOption<Limiter>> {
+    Arc::new(ArcSwapOption::new(
+        rate_limit.map(|config| Arc::new(limiter(config))),
+    ))
+}
+
+/// Rejects clients that exceed their quota with 429 and a `Retry-After` header.
+pub async fn limit<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let settings = state.settings.load();
+    let limiter = state.rate_limiter.load();
+    let (Some(limiter), Some(config)) = (&*limiter, &settings.rate_limit) else {
+        return next.run(req).await;
+    };
+
+    let key = config
+        .key_header
+        .as_ref()
+        .and_then(|name| req.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| {
+            req.extensions()
+                .get::<ClientIp>()
+                .map(|ClientIp(ip)| ip.to_string())
+        })
+        .unwrap_or_default();
+
+    match limiter.check_key(&key) {
+        Ok(()) => next.run(req).await,
+        Err(not_until) => {
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            let mut response = error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "rate limit exceeded",
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+    }
+}
+
+/// Periodically forgets clients whose quota has fully replenished.
+pub async fn prune(limiters: Arc<ArcSwapOption<Limiter>>, shutdown: CancellationToken) {
+    while shutdown::sleep(&shutdown, Duration::from_secs(60)).await {
+        if let Some(limiter) = &*limiters.load() {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use crate::{app, send, test_settings};
+    use axum::body::Body;
+    use std::num::NonZeroU32;
+
+    fn from(client: &str) -> Request<Body> {
+        Request::get("/")
+            .header("x-client-id", client)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn throttles_a_client_over_its_burst() {
+        let settings = crate::Settings {
+            rate_limit: Some(RateLimit {
+                per_second: NonZeroU32::new(1).unwrap(),
+                burst: NonZeroU32::new(2).unwrap(),
+                key_header: Some("x-client-id".into()),
+            }),
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(
+            settings,
+            Arc::new(MockSecretStore::new()),
+        ));
+
+        for _ in 0..2 {
+            let (status, _) = send(router.clone(), from("billing")).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let response = tower::ServiceExt::oneshot(router.clone(), from("billing"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        // Other clients have quotas of their own
+        let (status, _) = send(router, from("payments")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::rate_limit;
+use crate::settings::Settings;
+use crate::telemetry::{self, LogFilterHandle};
+use crate::transit;
+use crate::vault;
+use crate::AppState;
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::EnvFilter;
+
+/// Re-reads the configuration whenever SIGHUP is received.
+pub async fn on_sighup(state: AppState, config_file: Option<PathBuf>, shutdown: CancellationToken) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = hangup.recv() => {}
+        }
+
+        tracing::warn!("SIGHUP received, reloading configuration");
+        match reload(&state, config_file.as_deref()).await {
+            Ok(()) => {
+                tracing::info!(settings = %state.settings.load().redacted(), "configuration reloaded")
+            }
+            Err(e) => tracing::error!("failed to reload configuration, keeping previous: {:#}", e),
+        }
+    }
+}
+
+/// Cycles the log level from the configured filter through debug and trace and back on SIGUSR1.
+pub async fn on_sigusr1(
+    settings: Arc<ArcSwap<Settings>>,
+    log_filter: LogFilterHandle,
+    shutdown: CancellationToken,
+) {
+    let mut user1 = signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 handler");
+    let mut level = None;
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = user1.recv() => {}
+        }
+
+        level = next_level(level);
+        let filter = match level {
+            // Keep the spans that trace context is attached to
+            Some(level) => EnvFilter::try_new(format!("{},otel::tracing=trace", level)),
+            None => telemetry::env_filter(&settings.load()),
+        };
+        let result = filter
+            .map_err(anyhow::Error::from)
+            .and_then(|filter| Ok(log_filter.reload(filter)?));
+        match result {
+            Ok(()) => tracing::warn!(
+                "SIGUSR1 received, log level now {}",
+                level.unwrap_or("as configured")
+            ),
+            Err(e) => tracing::error!("failed to change log level: {:#}", e),
+        }
+    }
+}
+
+fn next_level(level: Option<&'static str>) -> Option<&'static str> {
+    match level {
+        None => Some("debug"),
+        Some("debug") => Some("trace"),
+        Some(_) => None,
+    }
+}
+
+/// Applies the reloadable fields of the configuration, keeping all of the previous one when
+/// any of it fails, including connecting to a new `vault_address`.
+///
+/// Requests already using the previous Vault client finish with it, and a changed
+/// `rate_limit` starts every client with a full quota.
+pub async fn reload(state: &AppState, config_file: Option<&Path>) -> anyhow::Result<()> {
+    let new = transit::decrypt(Settings::new(config_file)?).await?;
+    let previous = state.settings.load_full();
+    let reloaded = previous.reloaded(&new)?;
+    let filter = telemetry::env_filter(&reloaded)?;
+
+    if reloaded.vault_address != previous.vault_address {
+        tracing::warn!(
+            "vault_address changed from {} to {}, reconnecting",
+            previous.vault_address,
+            reloaded.vault_address
+        );
+        let (client, lease) = vault::connect(&reloaded)
+            .await
+            .with_context(|| format!("failed to connect to Vault at {}", reloaded.vault_address))?;
+        state.renewer.replace(&state.vault, client, lease).await;
+        state.vault_connected.store(true, Ordering::Release);
+        tracing::warn!("now using Vault at {}", reloaded.vault_address);
+    }
+
+    state.log_filter.reload(filter)?;
+    if reloaded.rate_limit != previous.rate_limit {
+        tracing::warn!("rate_limit changed, replacing the rate limiter");
+        state.rate_limiter.store(
+            reloaded
+                .rate_limit
+                .as_ref()
+                .map(|config| Arc::new(rate_limit::limiter(config))),
+        );
+    }
+    state.settings.store(Arc::new(reloaded));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tracing_subscriber::EnvFilter;
+
+    /// Counts the events that get past the filter.
+    #[derive(Clone, Default)]
+    struct Events(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Events {
+        fn on_event(&self, _: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn swapping_the_filter_changes_the_active_level() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let events = Events::default();
+        let (filter, handle): (_, LogFilterHandle) =
+            tracing_subscriber::reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(events.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        tracing::debug!("hidden");
+        assert_eq!(events.0.load(Ordering::SeqCst), 0);
+
+        handle.reload(EnvFilter::new("debug")).unwrap();
+        tracing::debug!("shown");
+        assert_eq!(events.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn level_cycles_through_debug_and_trace_and_back() {
+        let mut level = None;
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            level = next_level(level);
+            seen.push(level);
+        }
+
+        assert_eq!(seen, [Some("debug"), Some("trace"), None, Some("debug")]);
+    }
+
+    #[tokio::test]
+    async fn reloading_a_rate_limit_applies_it() {
+        use crate::store::MockSecretStore;
+        use crate::{app, send, test_settings, AppState};
+        use axum::{body::Body, http::Request, http::StatusCode};
+
+        let dir = std::env::temp_dir().join(format!("spot-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spot.toml");
+        std::fs::write(
+            &path,
+            "auth_enabled = false\n\n[rate_limit]\nper_second = 1\nburst = 1\n",
+        )
+        .unwrap();
+        let state = AppState::for_tests(test_settings(), Arc::new(MockSecretStore::new()));
+        let router = app(state.clone());
+        let read = || Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        assert_eq!(send(router.clone(), read()).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(router.clone(), read()).await.0, StatusCode::NOT_FOUND);
+
+        reload(&state, Some(&path)).await.unwrap();
+
+        assert_eq!(send(router.clone(), read()).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(router, read()).await.0, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
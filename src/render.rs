@@ -0,0 +1,163 @@
+//! Renders the Handlebars templates posted to `POST /render`.
+
+use crate::error::AppError;
+use crate::timing;
+use handlebars::template::{Template, TemplateElement};
+use handlebars::{Handlebars, RenderErrorReason};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Deepest nesting of blocks and subexpressions accepted in a template.
+const MAX_NESTING: usize = 32;
+
+/// Parses `source`, rejecting partials since an inline partial can include itself forever.
+pub fn compile(source: &str) -> Result<Template, AppError> {
+    if nesting(source) > MAX_NESTING {
+        return Err(AppError::BadRequest(format!(
+            "templates may nest blocks and subexpressions at most {} deep",
+            MAX_NESTING
+        )));
+    }
+    let template = Template::compile(source)
+        .map_err(|e| AppError::BadRequest(format!("invalid template: {}", e)))?;
+    if has_partials(&template) {
+        return Err(AppError::BadRequest(
+            "partials are not supported in templates".into(),
+        ));
+    }
+    Ok(template)
+}
+
+/// Roughly how deep blocks and subexpressions nest in `source`, checked before parsing since
+/// both the parser and the renderer recurse once per level.
+fn nesting(source: &str) -> usize {
+    let (mut deepest, mut blocks) = (0, 0usize);
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let tag = rest[start + 2..start + end].trim_start_matches(['{', '~']);
+        match tag.chars().next() {
+            // A bare `{{^}}` is an `{{else}}`
+            Some('^') if tag[1..].trim_matches([' ', '~']).is_empty() => {}
+            Some('#' | '^') => blocks += 1,
+            Some('/') => blocks = blocks.saturating_sub(1),
+            _ => {}
+        }
+        let mut parens = 0usize;
+        for c in tag.chars() {
+            match c {
+                '(' => parens += 1,
+                ')' => parens = parens.saturating_sub(1),
+                _ => {}
+            }
+            deepest = deepest.max(blocks + parens);
+        }
+        deepest = deepest.max(blocks);
+        rest = &rest[start + end + 2..];
+    }
+    deepest
+}
+
+fn has_partials(template: &Template) -> bool {
+    template.elements.iter().any(|element| match element {
+        TemplateElement::PartialExpression(_) | TemplateElement::PartialBlock(_) => true,
+        TemplateElement::HelperBlock(helper) => helper
+            .template
+            .iter()
+            .chain(&helper.inverse)
+            .any(has_partials),
+        TemplateElement::DecoratorBlock(decorator) => decorator.template.iter().any(has_partials),
+        _ => false,
+    })
+}
+
+/// Renders `template` with `context`, without HTML escaping, failing with
+/// [`AppError::MissingSecrets`] for a variable that is not in `context`.
+///
+/// Rendering runs on a blocking thread; after `limit` the request gives up on it, though the
+/// thread runs on until the rendering ends.
+pub async fn render(
+    template: Template,
+    context: Value,
+    limit: Duration,
+) -> Result<String, AppError> {
+    let rendering = tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let mut registry = Handlebars::new();
+        registry.set_strict_mode(true);
+        registry.register_escape_fn(handlebars::no_escape);
+        registry.register_template("template", template);
+        let rendered = registry.render("template", &context);
+        (rendered, started.elapsed())
+    });
+
+    let (rendered, elapsed) = tokio::time::timeout(limit, rendering)
+        .await
+        .map_err(|_| {
+            AppError::Timeout(format!(
+                "rendering the template took longer than {}s",
+                limit.as_secs()
+            ))
+        })?
+        .map_err(|e| anyhow::anyhow!("rendering the template failed: {}", e))?;
+    timing::record("render", elapsed);
+
+    rendered.map_err(|e| match e.reason() {
+        RenderErrorReason::MissingVariable(Some(path)) => {
+            AppError::MissingSecrets(vec![path.clone()])
+        }
+        _ => AppError::BadRequest(format!("failed to render the template: {}", e)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MockSecretStore;
+    use crate::{app, test_settings, AppState};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn render(template: &str) -> (StatusCode, String) {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let router = app(AppState::for_tests(test_settings(), Arc::new(store)));
+        let request = Request::post("/render")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({ "template" : template, "paths" : ["app/db"] }).to_string(),
+            ))
+            .unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn renders_a_template_with_a_secret() {
+        let (status, body) =
+            render("DATABASE_URL=postgres://app:{{[app/db].password}}@db/app").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "DATABASE_URL=postgres://app:hunter2@db/app");
+    }
+
+    #[tokio::test]
+    async fn missing_key_is_unprocessable() {
+        let (status, body) = render("{{[app/db].username}}").await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.contains("missing_secrets"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn partials_are_rejected() {
+        let (status, _) = render("{{> header}}").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,77 @@
+//! The routes registered by [`crate::app`], recorded as they are added so that
+//! `GET /admin/routes` lists exactly what the router serves.
+
+use crate::AppState;
+use axum::{http::Method, routing::MethodRouter, Router};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Route {
+    pub path: String,
+    pub methods: Vec<String>,
+}
+
+/// A router that records the path and methods of each route added through it.
+pub struct Routes {
+    router: Router<AppState>,
+    registered: Vec<Route>,
+}
+
+impl Routes {
+    pub fn new(router: Router<AppState>) -> Self {
+        Routes {
+            router,
+            registered: Vec::new(),
+        }
+    }
+
+    /// Adds `service` at `path`; `methods` are the ones it answers, since a `MethodRouter`
+    /// cannot be asked.
+    pub fn route(
+        mut self,
+        path: &str,
+        methods: &[Method],
+        service: MethodRouter<AppState>,
+    ) -> Self {
+        self.router = self.router.route(path, service);
+        self.record(path, methods)
+    }
+
+    /// Merges `router`, listed as answering `methods` under `path`.
+    pub fn merge(mut self, path: &str, methods: &[Method], router: Router<AppState>) -> Self {
+        self.router = self.router.merge(router);
+        self.record(path, methods)
+    }
+
+    /// Applies layers, or anything else that adds no route, to the routes added so far.
+    pub fn map(mut self, f: impl FnOnce(Router<AppState>) -> Router<AppState>) -> Self {
+        self.router = f(self.router);
+        self
+    }
+
+    /// The router, and the routes it serves with `base_path` prepended, ordered by path.
+    pub fn finish(self, base_path: &str) -> (Router<AppState>, Vec<Route>) {
+        let mut registered: Vec<Route> = self
+            .registered
+            .into_iter()
+            .map(|route| Route {
+                // Nesting serves `/` at the base path itself
+                path: match route.path.as_str() {
+                    "/" if !base_path.is_empty() => base_path.to_owned(),
+                    path => format!("{}{}", base_path, path),
+                },
+                ..route
+            })
+            .collect();
+        registered.sort_by(|a, b| a.path.cmp(&b.path));
+        (self.router, registered)
+    }
+
+    fn record(mut self, path: &str, methods: &[Method]) -> Self {
+        self.registered.push(Route {
+            path: path.to_owned(),
+            methods: methods.iter().map(ToString::to_string).collect(),
+        });
+        self
+    }
+}
@@ -0,0 +1,1678 @@
+use crate::audit;
+use crate::auth::Caller;
+use crate::cache;
+use crate::coalesce;
+use crate::error::{error_response, AppError};
+use crate::render;
+use crate::settings::MissingFieldPolicy;
+use crate::timing::Timed;
+use crate::vault;
+use crate::AppState;
+use async_trait::async_trait;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+use utoipa::ToSchema;
+use vaultrs::error::ClientError;
+
+/// Secret reads in progress by namespace, mount and path.
+pub type Reads = coalesce::SingleFlight<cache::Key, Result<Value, AppError>>;
+
+/// Serves 503 until Vault has been reached after a fail-open start.
+pub async fn require_vault<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if state.vault_connected.load(Ordering::Acquire) {
+        next.run(req).await
+    } else {
+        error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "vault_unavailable",
+            "not connected to Vault yet",
+        )
+    }
+}
+
+/// Answers 403 before any Vault call, and before the body is read, when the request's
+/// [`Mount`] is not an allowed mount.
+pub async fn require_allowed_mount<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match Mount::resolve(&state, req.headers()) {
+        Ok(_) => next.run(req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The KV mount selected with `X-Vault-Mount`, or `vault_mount` without one.
+pub struct Mount(String);
+
+impl Mount {
+    fn resolve(state: &AppState, headers: &HeaderMap) -> Result<Self, AppError> {
+        let settings = state.settings.load();
+        let mount = match headers.get("x-vault-mount") {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| AppError::BadRequest("invalid X-Vault-Mount header".into()))?
+                .trim_matches('/'),
+            None => settings.vault_mount.as_str(),
+        };
+        // The mount is part of the Vault URL, so it must not climb out of it either
+        let invalid_segment =
+            |segment: &str| segment.is_empty() || segment == "." || segment == "..";
+        if mount.split('/').any(invalid_segment) || !state.path_pattern.is_match(mount) {
+            return Err(AppError::BadRequest(format!("invalid mount {:?}", mount)));
+        }
+        if !settings.mount_allowed(mount) {
+            return Err(AppError::Forbidden(format!(
+                "mount {:?} is not an allowed mount",
+                mount
+            )));
+        }
+        Ok(Mount(mount.to_owned()))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Mount {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        Mount::resolve(state, &parts.headers)
+    }
+}
+
+/// The namespace selected with `X-Vault-Namespace`, if it is in `vault_namespace_overrides`.
+pub struct Namespace(Option<String>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Namespace {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let Some(value) = parts.headers.get("x-vault-namespace") else {
+            return Ok(Namespace(None));
+        };
+        let namespace = value
+            .to_str()
+            .map_err(|_| AppError::BadRequest("invalid X-Vault-Namespace header".into()))?;
+        if !state
+            .settings
+            .load()
+            .vault_namespace_overrides
+            .iter()
+            .any(|allowed| allowed == namespace)
+        {
+            return Err(AppError::Forbidden(format!(
+                "namespace {:?} may not be selected per request",
+                namespace
+            )));
+        }
+        Ok(Namespace(Some(namespace.to_owned())))
+    }
+}
+
+/// How a secret read is returned, chosen by the `Accept` header.
+pub enum Format {
+    Json,
+    /// `KEY='value'` lines that a shell can source, for `text/plain` or `text/x-dotenv`.
+    Dotenv,
+    /// The same structure as the JSON, encoded as MessagePack.
+    Msgpack,
+}
+
+#[async_trait]
+impl<S: Sync> FromRequestParts<S> for Format {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, AppError> {
+        let Some(accept) = parts.headers.get(header::ACCEPT) else {
+            return Ok(Format::Json);
+        };
+        let accept = accept
+            .to_str()
+            .map_err(|_| AppError::BadRequest("invalid Accept header".into()))?;
+
+        // The first of the most preferred media types that is supported wins
+        let mut best: Option<(f32, Format)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.as_str() {
+                "application/json" | "application/*" | "*/*" => Format::Json,
+                "text/plain" | "text/x-dotenv" | "text/*" => Format::Dotenv,
+                "application/msgpack" | "application/x-msgpack" => Format::Msgpack,
+                _ => continue,
+            };
+            if quality > 0.0 && best.as_ref().is_none_or(|(q, _)| quality > *q) {
+                best = Some((quality, format));
+            }
+        }
+        best.map(|(_, format)| format).ok_or_else(|| {
+            AppError::NotAcceptable(format!(
+                "cannot return a secret as {}, only as application/json, application/msgpack or text/plain",
+                accept
+            ))
+        })
+    }
+}
+
+/// A read's result in the [`Format`] the client asked for.
+pub enum Rendered {
+    Json(Value),
+    Dotenv(String),
+    Msgpack(Vec<u8>),
+}
+
+impl Format {
+    fn render(&self, secret: Value) -> Result<Rendered, AppError> {
+        match self {
+            Format::Json => Ok(Rendered::Json(secret)),
+            Format::Dotenv => dotenv(&secret).map(Rendered::Dotenv),
+            Format::Msgpack => msgpack(&secret),
+        }
+    }
+
+    /// Renders results without a `text/plain` form, such as listings.
+    fn structured(&self, value: Value) -> Result<Rendered, AppError> {
+        match self {
+            Format::Json => Ok(Rendered::Json(value)),
+            Format::Msgpack => msgpack(&value),
+            Format::Dotenv => Err(AppError::NotAcceptable(
+                "listings and wrapped reads cannot be returned as text/plain, only as application/json or application/msgpack".into(),
+            )),
+        }
+    }
+}
+
+/// Encodes maps with their keys, so that they decode to the same structure as the JSON.
+fn msgpack(value: &Value) -> Result<Rendered, AppError> {
+    rmp_serde::to_vec_named(value)
+        .map(Rendered::Msgpack)
+        .map_err(|e| AppError::Internal(anyhow::anyhow!("failed to encode as msgpack: {}", e)))
+}
+
+impl IntoResponse for Rendered {
+    fn into_response(self) -> Response {
+        match self {
+            Rendered::Json(value) => Json(value).into_response(),
+            Rendered::Dotenv(lines) => {
+                ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], lines).into_response()
+            }
+            Rendered::Msgpack(bytes) => {
+                ([(header::CONTENT_TYPE, "application/msgpack")], bytes).into_response()
+            }
+        }
+    }
+}
+
+/// Renders a flat map of strings as single-quoted shell assignments.
+fn dotenv(secret: &Value) -> Result<String, AppError> {
+    let not_flat = || {
+        AppError::NotAcceptable(
+            "only secrets whose values are all strings can be returned as text/plain".into(),
+        )
+    };
+    let Value::Object(secret) = secret else {
+        return Err(not_flat());
+    };
+    let mut lines = String::new();
+    for (key, value) in secret {
+        let Value::String(value) = value else {
+            return Err(not_flat());
+        };
+        let valid_name = key.chars().next().is_some_and(|c| !c.is_ascii_digit())
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(AppError::NotAcceptable(format!(
+                "key {:?} is not a valid environment variable name",
+                key
+            )));
+        }
+        lines.push_str(&format!("{}='{}'\n", key, value.replace('\'', "'\\''")));
+    }
+    Ok(lines)
+}
+
+#[derive(Deserialize)]
+pub struct ReadParams {
+    /// Lists the keys under `path` instead of reading the secret at it.
+    #[serde(default)]
+    list: bool,
+    /// Returns a response-wrapping token valid for this long instead of the secret.
+    wrap_ttl: Option<String>,
+    /// Reads this version instead of the latest, on KV v2 mounts.
+    version: Option<String>,
+    /// Returns only these comma-separated keys of the secret.
+    fields: Option<String>,
+}
+
+/// Reads a secret, or lists the keys below a path.
+#[utoipa::path(
+    get,
+    path = "/secret/{path}",
+    params(
+        ("path" = String, Path, description = "Secret path below the mount"),
+        ("list" = Option<bool>, Query, description = "List the child keys instead, with sub-directories ending in `/`"),
+        ("wrap_ttl" = Option<String>, Query, description = "Return a Vault response-wrapping token valid for this long, e.g. `5m`, instead of the secret"),
+        ("version" = Option<u64>, Query, description = "Read this version of the secret instead of the latest, on KV v2 mounts"),
+        ("fields" = Option<String>, Query, description = "Return only these comma-separated keys of the secret"),
+        ("X-Vault-Namespace" = Option<String>, Header, description = "Namespace from `vault_namespace_overrides` to use instead of `vault_namespace`"),
+        ("X-Vault-Mount" = Option<String>, Header, description = "KV mount to use instead of `vault_mount`, if allowed by `allowed_mounts`"),
+    ),
+    responses(
+        (status = 200, description = "The secret's data, an array of keys when listing, or `wrap_info` when wrapping; with `Accept: text/plain`, the secret as `KEY='value'` lines, and with `Accept: application/msgpack`, any of these as MessagePack", body = Object),
+        (status = 400, description = "Invalid `wrap_ttl`, `version` or `fields`", body = crate::openapi::ErrorBody),
+        (status = 404, description = "No secret at, or under, `path`, or no such version", body = crate::openapi::ErrorBody),
+        (status = 406, description = "A nested secret, listing or wrapped read was requested as `text/plain`, or no supported type is accepted", body = crate::openapi::ErrorBody),
+        (status = 422, description = "The secret lacks some of `fields`, unless `missing_field_policy` is `omit`", body = crate::openapi::ErrorBody),
+    )
+)]
+pub async fn read(
+    State(state): State<AppState>,
+    Namespace(namespace): Namespace,
+    Mount(mount): Mount,
+    caller: Caller,
+    format: Format,
+    Path(path): Path<String>,
+    Query(params): Query<ReadParams>,
+) -> Result<Timed<Rendered>, AppError> {
+    let namespace = namespace.as_deref();
+    let fields = params.fields.as_deref().map(parse_fields).transpose()?;
+    if fields.is_some() && (params.list || params.wrap_ttl.is_some()) {
+        return Err(AppError::BadRequest(
+            "fields cannot be combined with list or wrap_ttl".into(),
+        ));
+    }
+    let policy = state.settings.load().missing_field_policy;
+    if let Some(version) = &params.version {
+        if params.list || params.wrap_ttl.is_some() {
+            return Err(AppError::BadRequest(
+                "version cannot be combined with list or wrap_ttl".into(),
+            ));
+        }
+        let result = traced(
+            &mount,
+            "vault.read_version",
+            &path,
+            read_version(&state, namespace, &mount, &path, version),
+        )
+        .await
+        .and_then(|secret| project(secret, fields.as_deref(), policy))
+        .and_then(|secret| format.render(secret));
+        audit(&state, &caller, namespace, &mount, "GET", &path, &result);
+        return Ok(Timed(result?));
+    }
+    if params.list {
+        let result = traced(
+            &mount,
+            "vault.list",
+            &path,
+            list(&state, namespace, &mount, path.trim_end_matches('/')),
+        )
+        .await
+        .and_then(|result| format.structured(result));
+        audit(&state, &caller, namespace, &mount, "LIST", &path, &result);
+        return Ok(Timed(result?));
+    }
+    if let Some(ttl) = &params.wrap_ttl {
+        let result = traced(
+            &mount,
+            "vault.read_wrapped",
+            &path,
+            wrapped(&state, namespace, &mount, &path, ttl),
+        )
+        .await
+        .and_then(|result| format.structured(result));
+        audit(&state, &caller, namespace, &mount, "GET", &path, &result);
+        return Ok(Timed(result?));
+    }
+    let result = traced(
+        &mount,
+        "vault.read",
+        &path,
+        fetch(&state, namespace, &mount, &path),
+    )
+    .await
+    .and_then(|secret| project(secret, fields.as_deref(), policy))
+    .and_then(|secret| format.render(secret));
+    audit(&state, &caller, namespace, &mount, "GET", &path, &result);
+    Ok(Timed(result?))
+}
+
+/// Child keys of a directory, with sub-directories ending in `/`.
+async fn list(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+) -> Result<Value, AppError> {
+    validate_path(state, path)?;
+
+    let result = state.store.list(namespace, mount, path).await;
+    metrics::increment_counter!("vault_lists_total", "outcome" => vault::outcome(&result));
+
+    // Vault answers 404 both for missing prefixes and for leaf secrets, which have no children
+    let keys = result.map_err(|e| match e {
+        ClientError::APIError { code: 404, .. } => {
+            AppError::VaultNotFound(format!("no secrets under {}", path))
+        }
+        e => e.into(),
+    })?;
+    Ok(json!(keys))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BatchRead {
+    paths: Vec<String>,
+}
+
+/// Reads several secrets concurrently, reporting failures per path.
+#[utoipa::path(
+    post,
+    path = "/secrets/batch",
+    params(
+        ("X-Vault-Namespace" = Option<String>, Header, description = "Namespace from `vault_namespace_overrides` to use instead of `vault_namespace`"),
+        ("X-Vault-Mount" = Option<String>, Header, description = "KV mount to use instead of `vault_mount`, if allowed by `allowed_mounts`"),
+    ),
+    request_body = BatchRead,
+    responses(
+        (status = 200, description = "Secrets and errors keyed by path", body = crate::openapi::BatchResult),
+        (status = 400, description = "Too many paths", body = crate::openapi::ErrorBody),
+    )
+)]
+pub async fn batch(
+    State(state): State<AppState>,
+    Namespace(namespace): Namespace,
+    Mount(mount): Mount,
+    caller: Caller,
+    body: Result<Json<BatchRead>, JsonRejection>,
+) -> Result<Timed<Json<Value>>, AppError> {
+    let settings = state.settings.load();
+    let Json(BatchRead { paths }) =
+        body.map_err(|rejection| AppError::body(rejection, settings.max_body_bytes))?;
+    if paths.len() > settings.batch_max_paths {
+        return Err(AppError::BadRequest(format!(
+            "batch of {} paths exceeds the limit of {}",
+            paths.len(),
+            settings.batch_max_paths
+        )));
+    }
+
+    let paths: BTreeSet<String> = paths.into_iter().collect();
+    let fetched = fetch_all(&state, &caller, namespace.as_deref(), &mount, &paths).await;
+
+    let mut results = Map::new();
+    let mut errors = Map::new();
+    for (path, result) in paths.into_iter().zip(fetched) {
+        match result {
+            Ok(secret) => {
+                results.insert(path, secret);
+            }
+            Err(e) => {
+                errors.insert(
+                    path,
+                    json!({ "code" : e.code(), "message" : e.to_string() }),
+                );
+            }
+        }
+    }
+    Ok(Timed(Json(
+        json!({ "results" : results, "errors" : errors }),
+    )))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RenderRequest {
+    /// Handlebars template, with each secret under its path, e.g. `{{[app/db].password}}`.
+    template: String,
+    /// Secrets the template refers to.
+    paths: Vec<String>,
+}
+
+/// Renders a Handlebars template with the given secrets, e.g. into a config file.
+#[utoipa::path(
+    post,
+    path = "/render",
+    params(
+        ("X-Vault-Namespace" = Option<String>, Header, description = "Namespace from `vault_namespace_overrides` to use instead of `vault_namespace`"),
+        ("X-Vault-Mount" = Option<String>, Header, description = "KV mount to use instead of `vault_mount`, if allowed by `allowed_mounts`"),
+    ),
+    request_body = RenderRequest,
+    responses(
+        (status = 200, description = "The rendered template", body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid template or too many paths", body = crate::openapi::ErrorBody),
+        (status = 413, description = "Template too large", body = crate::openapi::ErrorBody),
+        (status = 422, description = "Secrets or keys the template needs do not exist", body = crate::openapi::ErrorBody),
+        (status = 504, description = "Rendering took too long", body = crate::openapi::ErrorBody),
+    )
+)]
+pub async fn render(
+    State(state): State<AppState>,
+    Namespace(namespace): Namespace,
+    Mount(mount): Mount,
+    caller: Caller,
+    body: Result<Json<RenderRequest>, JsonRejection>,
+) -> Result<Timed<impl IntoResponse>, AppError> {
+    let settings = state.settings.load_full();
+    let Json(RenderRequest { template, paths }) =
+        body.map_err(|rejection| AppError::body(rejection, settings.max_body_bytes))?;
+    if template.len() > settings.render_max_template_bytes {
+        return Err(AppError::PayloadTooLarge(format!(
+            "template of {} bytes exceeds the limit of {}",
+            template.len(),
+            settings.render_max_template_bytes
+        )));
+    }
+    if paths.len() > settings.batch_max_paths {
+        return Err(AppError::BadRequest(format!(
+            "{} paths exceed the limit of {}",
+            paths.len(),
+            settings.batch_max_paths
+        )));
+    }
+    let template = render::compile(&template)?;
+
+    let paths: BTreeSet<String> = paths.into_iter().collect();
+    let fetched = fetch_all(&state, &caller, namespace.as_deref(), &mount, &paths).await;
+    let mut context = Map::new();
+    let mut missing = Vec::new();
+    for (path, result) in paths.into_iter().zip(fetched) {
+        match result {
+            Ok(secret) => {
+                context.insert(path, secret);
+            }
+            Err(AppError::VaultNotFound(_)) => missing.push(path),
+            Err(e) => return Err(e),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(AppError::MissingSecrets(missing));
+    }
+
+    let limit = Duration::from_secs(settings.render_timeout_secs);
+    let rendered = render::render(template, Value::Object(context), limit).await?;
+    Ok(Timed((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        rendered,
+    )))
+}
+
+/// Reads `paths` like a batch, at most `batch_concurrency` at a time, auditing each read.
+async fn fetch_all(
+    state: &AppState,
+    caller: &Caller,
+    namespace: Option<&str>,
+    mount: &str,
+    paths: &BTreeSet<String>,
+) -> Vec<Result<Value, AppError>> {
+    let permits = Semaphore::new(state.settings.load().batch_concurrency.max(1));
+    let fetched = futures::future::join_all(paths.iter().map(|path| async {
+        let _permit = permits.acquire().await.expect("semaphore is never closed");
+        traced(
+            mount,
+            "vault.read",
+            path,
+            fetch(state, namespace, mount, path),
+        )
+        .await
+    }))
+    .await;
+    for (path, result) in paths.iter().zip(&fetched) {
+        audit(state, caller, namespace, mount, "POST", path, result);
+    }
+    fetched
+}
+
+/// Reads a secret through the cache, if enabled.
+async fn fetch(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+) -> Result<Value, AppError> {
+    validate_path(state, path)?;
+    record_access(state, mount, path);
+
+    let key = cache::key(namespace, mount, path);
+    if let Some(cache) = &state.cache {
+        if let Some(secret) = cache.get(&key).await {
+            metrics::increment_counter!("secret_cache_requests_total", "result" => "hit");
+            return Ok(secret);
+        }
+        metrics::increment_counter!("secret_cache_requests_total", "result" => "miss");
+    }
+
+    state.reads.run(key.clone(), load(state.clone(), key)).await
+}
+
+/// Reads a secret from the store into the cache, once for all concurrent reads of it.
+///
+/// The read is shared, so it gets a deadline of its own rather than that of whichever request
+/// happens to poll it.
+async fn load(state: AppState, key: cache::Key) -> Result<Value, AppError> {
+    let (namespace, mount, path) = &key;
+    let generation = state.cache.as_ref().map(|cache| cache.generation(&key));
+    let limit = Duration::from_secs(state.settings.load().request_timeout_secs);
+    let deadline = tokio::time::Instant::now() + limit;
+    let read = state.store.read(namespace.as_deref(), mount, path);
+    let result = vault::DEADLINE.scope(deadline, read).await;
+    metrics::increment_counter!("vault_reads_total", "outcome" => vault::outcome(&result));
+
+    let secret = result.map_err(|e| match e {
+        ClientError::APIError { code: 404, .. } => {
+            AppError::VaultNotFound(format!("secret {} not found", path))
+        }
+        e => e.into(),
+    })?;
+    if let (Some(cache), Some(generation)) = (&state.cache, generation) {
+        cache.insert(key, secret.clone(), generation).await;
+    }
+    Ok(secret)
+}
+
+/// Reads an older version of a secret, always from Vault since the cache only holds the
+/// latest.
+async fn read_version(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+    version: &str,
+) -> Result<Value, AppError> {
+    validate_path(state, path)?;
+    let version = parse_version(version)?;
+    if state.settings.load().vault_kv_version == 1 {
+        return Err(AppError::BadRequest("KV v1 mounts keep no versions".into()));
+    }
+    record_access(state, mount, path);
+
+    let result = state
+        .store
+        .read_version(namespace, mount, path, version)
+        .await;
+    metrics::increment_counter!("vault_reads_total", "outcome" => vault::outcome(&result));
+
+    result.map_err(|e| match e {
+        ClientError::APIError { code: 404, .. } => {
+            AppError::VaultNotFound(format!("secret {} has no version {}", path, version))
+        }
+        e => e.into(),
+    })
+}
+
+/// Has Vault wrap the secret in a single-use token for the final consumer to unwrap, so that
+/// spot never returns the plaintext.
+///
+/// Wrapped reads always go to Vault, bypassing the cache.
+async fn wrapped(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+    ttl: &str,
+) -> Result<Value, AppError> {
+    validate_path(state, path)?;
+    validate_ttl(ttl)?;
+    record_access(state, mount, path);
+
+    let result = state.store.read_wrapped(namespace, mount, path, ttl).await;
+    metrics::increment_counter!("vault_reads_total", "outcome" => vault::outcome(&result));
+
+    let info = result.map_err(|e| match e {
+        ClientError::APIError { code: 404, .. } => {
+            AppError::VaultNotFound(format!("secret {} not found", path))
+        }
+        e => e.into(),
+    })?;
+    Ok(json!({
+        "wrap_info" : {
+            "token" : info.token,
+            "accessor" : info.accessor,
+            "ttl" : info.ttl,
+            "creation_time" : info.creation_time,
+            "creation_path" : info.creation_path,
+        }
+    }))
+}
+
+/// Writes a new version of a secret.
+#[utoipa::path(
+    put,
+    path = "/secret/{path}",
+    params(
+        ("path" = String, Path, description = "Secret path below the mount"),
+        ("X-Vault-Namespace" = Option<String>, Header, description = "Namespace from `vault_namespace_overrides` to use instead of `vault_namespace`"),
+        ("X-Vault-Mount" = Option<String>, Header, description = "KV mount to use instead of `vault_mount`, if allowed by `allowed_mounts`"),
+    ),
+    request_body(content = Object, description = "The secret's key/value data"),
+    responses(
+        (status = 200, description = "The version that was created", body = crate::openapi::VersionMetadata),
+        (status = 400, description = "The body is not a JSON object", body = crate::openapi::ErrorBody),
+        (status = 413, description = "The body exceeds `max_secret_bytes`", body = crate::openapi::ErrorBody),
+    )
+)]
+pub async fn write(
+    State(state): State<AppState>,
+    Namespace(namespace): Namespace,
+    Mount(mount): Mount,
+    caller: Caller,
+    Path(path): Path<String>,
+    body: Result<Json<Value>, JsonRejection>,
+) -> Result<Json<Value>, AppError> {
+    let namespace = namespace.as_deref();
+    let result = traced(
+        &mount,
+        "vault.write",
+        &path,
+        store(&state, namespace, &mount, &path, body),
+    )
+    .await;
+    audit(&state, &caller, namespace, &mount, "PUT", &path, &result);
+    result
+}
+
+async fn store(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+    body: Result<Json<Value>, JsonRejection>,
+) -> Result<Json<Value>, AppError> {
+    validate_path(state, path)?;
+    let Json(secret) = body
+        .map_err(|rejection| AppError::body(rejection, state.settings.load().max_secret_bytes))?;
+    let Value::Object(secret) = secret else {
+        return Err(AppError::BadRequest(
+            "secret body must be a JSON object".into(),
+        ));
+    };
+
+    let result = state.store.write(namespace, mount, path, &secret).await;
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics::increment_counter!("vault_writes_total", "outcome" => outcome);
+
+    let metadata = result?;
+    cache::invalidate(&state.cache, namespace, mount, path).await;
+    // KV v1 keeps no versions, so only `version` is reported, as null
+    Ok(Json(match metadata {
+        Some(metadata) => json!(metadata),
+        None => json!({ "version" : null }),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteParams {
+    /// Permanently removes every version and the metadata instead of a soft delete.
+    #[serde(default)]
+    destroy: bool,
+}
+
+/// Soft-deletes the latest version of a secret, or destroys all of them.
+///
+/// Deletes on KV v1 mounts are always permanent.
+#[utoipa::path(
+    delete,
+    path = "/secret/{path}",
+    params(
+        ("path" = String, Path, description = "Secret path below the mount"),
+        ("destroy" = Option<bool>, Query, description = "Permanently remove every version and the metadata"),
+        ("X-Vault-Namespace" = Option<String>, Header, description = "Namespace from `vault_namespace_overrides` to use instead of `vault_namespace`"),
+        ("X-Vault-Mount" = Option<String>, Header, description = "KV mount to use instead of `vault_mount`, if allowed by `allowed_mounts`"),
+    ),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 403, description = "Vault refused the delete", body = crate::openapi::ErrorBody),
+    )
+)]
+pub async fn delete(
+    State(state): State<AppState>,
+    Namespace(namespace): Namespace,
+    Mount(mount): Mount,
+    caller: Caller,
+    Path(path): Path<String>,
+    Query(params): Query<DeleteParams>,
+) -> Result<StatusCode, AppError> {
+    let namespace = namespace.as_deref();
+    let name = if params.destroy {
+        "vault.destroy"
+    } else {
+        "vault.delete"
+    };
+    let result = traced(
+        &mount,
+        name,
+        &path,
+        remove(&state, namespace, &mount, &path, params.destroy),
+    )
+    .await;
+    audit(&state, &caller, namespace, &mount, "DELETE", &path, &result);
+    result
+}
+
+async fn remove(
+    state: &AppState,
+    namespace: Option<&str>,
+    mount: &str,
+    path: &str,
+    destroy: bool,
+) -> Result<StatusCode, AppError> {
+    validate_path(state, path)?;
+
+    let result = if destroy {
+        state.store.destroy(namespace, mount, path).await
+    } else {
+        state.store.delete_latest(namespace, mount, path).await
+    };
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics::increment_counter!("vault_deletes_total", "outcome" => outcome);
+
+    result.map_err(|e| match e {
+        ClientError::APIError {
+            code: 401 | 403, ..
+        } => AppError::Forbidden(format!(
+            "not permitted to delete secret {}: {}",
+            path,
+            vault::reason(e)
+        )),
+        e => e.into(),
+    })?;
+    cache::invalidate(&state.cache, namespace, mount, path).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Counts reads per mount and allowed top-level prefix.
+fn record_access(state: &AppState, mount: &str, path: &str) {
+    let settings = state.settings.load();
+    let top = path.split('/').next().unwrap_or_default();
+    let prefix = if settings.access_metric_prefixes.iter().any(|p| p == top) {
+        top.to_owned()
+    } else {
+        "other".to_owned()
+    };
+    metrics::increment_counter!(
+        "secret_reads_by_prefix_total",
+        "mount" => mount.to_owned(),
+        "prefix" => prefix
+    );
+}
+
+/// Runs `operation` in a span named `name` for the trace backend, recording the mount, the
+/// top-level segment of `path` and the outcome.
+///
+/// Only the first segment is recorded since full paths can be sensitive.
+async fn traced<T>(
+    mount: &str,
+    name: &str,
+    path: &str,
+    operation: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    let span = tracing::info_span!(
+        "vault",
+        otel.name = name,
+        vault.mount = mount,
+        vault.path = path.split('/').next().unwrap_or_default(),
+        outcome = tracing::field::Empty,
+    );
+    let result = operation.instrument(span.clone()).await;
+    span.record(
+        "outcome",
+        result.as_ref().map_or_else(AppError::code, |_| "success"),
+    );
+    result
+}
+
+/// Records the outcome of a secret access in the audit log.
+fn audit<T>(
+    state: &AppState,
+    caller: &Caller,
+    namespace: Option<&str>,
+    mount: &str,
+    method: &str,
+    path: &str,
+    result: &Result<T, AppError>,
+) {
+    state.audit.write(&audit::Record {
+        method,
+        mount,
+        path,
+        namespace,
+        subject: caller.subject.as_deref(),
+        client_cert: caller.client_cert.as_deref(),
+        client_ip: caller.ip,
+        outcome: result.as_ref().map_or_else(AppError::code, |_| "success"),
+    });
+}
+
+/// Accepts the whole seconds, minutes or hours Vault takes for a wrapping TTL, e.g. `300` or `5m`.
+fn validate_ttl(ttl: &str) -> Result<(), AppError> {
+    let amount = ttl.strip_suffix(['s', 'm', 'h']).unwrap_or(ttl);
+    let digits = !amount.is_empty() && amount.bytes().all(|b| b.is_ascii_digit());
+    match amount.parse::<u64>() {
+        Ok(amount) if digits && amount > 0 => Ok(()),
+        _ => Err(AppError::BadRequest(format!(
+            "invalid wrap_ttl {:?}, expected a positive number of seconds, or minutes or hours \
+             with an m or h suffix",
+            ttl
+        ))),
+    }
+}
+
+/// The distinct names in a `fields` list such as `username,password`, in order.
+fn parse_fields(fields: &str) -> Result<Vec<String>, AppError> {
+    let mut names: Vec<String> = Vec::new();
+    for name in fields
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if !names.iter().any(|seen| seen == name) {
+            names.push(name.to_owned());
+        }
+    }
+    if names.is_empty() {
+        return Err(AppError::BadRequest(
+            "fields must name at least one field".into(),
+        ));
+    }
+    Ok(names)
+}
+
+/// Keeps only `fields` of `secret`, failing with the ones it lacks unless `policy` is to omit
+/// them.
+fn project(
+    secret: Value,
+    fields: Option<&[String]>,
+    policy: MissingFieldPolicy,
+) -> Result<Value, AppError> {
+    let Some(fields) = fields else {
+        return Ok(secret);
+    };
+    let mut data = match secret {
+        Value::Object(data) => data,
+        _ => Map::new(),
+    };
+    let missing: Vec<String> = fields
+        .iter()
+        .filter(|name| !data.contains_key(name.as_str()))
+        .cloned()
+        .collect();
+    if !missing.is_empty() && policy == MissingFieldPolicy::Reject {
+        return Err(AppError::MissingFields(missing));
+    }
+    Ok(Value::Object(
+        fields
+            .iter()
+            .filter_map(|name| data.remove_entry(name))
+            .collect(),
+    ))
+}
+
+/// Accepts a KV v2 version number, which starts at 1.
+fn parse_version(version: &str) -> Result<u64, AppError> {
+    let digits = version.bytes().all(|b| b.is_ascii_digit());
+    match version.parse::<u64>() {
+        Ok(number) if digits && number > 0 => Ok(number),
+        _ => Err(AppError::BadRequest(format!(
+            "invalid version {:?}, expected a positive integer",
+            version
+        ))),
+    }
+}
+
+/// Rejects paths that Vault should never see: empty, `.` or `..` segments, null bytes, more
+/// than `secret_path_max_depth` segments, or anything `secret_path_pattern` does not match.
+///
+/// `path` has already been percent-decoded, so encoded traversal is caught too.
+pub fn validate_path(state: &AppState, path: &str) -> Result<(), AppError> {
+    let invalid = |reason: &str| {
+        Err(AppError::BadRequest(format!(
+            "invalid secret path {:?}, {}",
+            path, reason
+        )))
+    };
+    if path.split('/').any(str::is_empty) {
+        return invalid("segments must not be empty");
+    }
+    if path
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return invalid("segments must not be . or ..");
+    }
+    if path.contains('\0') {
+        return invalid("it must not contain null bytes");
+    }
+    let max_depth = state.settings.load().secret_path_max_depth;
+    if path.split('/').count() > max_depth {
+        return invalid(&format!("it has more than {} segments", max_depth));
+    }
+    if !state.path_pattern.is_match(path) {
+        return invalid("it contains characters that are not allowed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{MissingFieldPolicy, MountPolicy};
+    use crate::store::MockSecretStore;
+    use crate::{app, fake_vault, send, test_settings, vault_response, AppState, Settings};
+    use axum::response::IntoResponse;
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        routing::{get, post},
+        Json, Router,
+    };
+    use futures::future::BoxFuture;
+    use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry::Key;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// The router of a state whose Vault serves `vault`.
+    async fn router(vault: Router, settings: Settings) -> axum::Router {
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            ..settings
+        };
+        app(AppState::against_vault(settings))
+    }
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn read(vault: Router, uri: &str) -> (StatusCode, Value) {
+        send(router(vault, test_settings()).await, get_request(uri)).await
+    }
+
+    fn put(uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::put(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    /// A fake Vault answering every request with 204, recording each as `METHOD /path`.
+    fn recording(calls: Arc<Mutex<Vec<String>>>) -> Router {
+        Router::new().fallback(move |request: Request<Body>| async move {
+            calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", request.method(), request.uri().path()));
+            StatusCode::NO_CONTENT
+        })
+    }
+
+    /// A fake Vault serving `app/db` until it is deleted, counting the reads.
+    fn serving_app_db(reads: Arc<AtomicUsize>) -> Router {
+        let deleted = Arc::new(AtomicBool::new(false));
+        Router::new().route(
+            "/v1/secret/data/app/db",
+            get({
+                let deleted = deleted.clone();
+                move || async move {
+                    reads.fetch_add(1, Ordering::SeqCst);
+                    if deleted.load(Ordering::SeqCst) {
+                        return (StatusCode::NOT_FOUND, Json(json!({ "errors" : [] })))
+                            .into_response();
+                    }
+                    vault_response(json!({
+                        "data" : { "password" : "hunter2" },
+                        "metadata" : metadata(1),
+                    }))
+                    .into_response()
+                }
+            })
+            .delete(move || async move {
+                deleted.store(true, Ordering::SeqCst);
+                StatusCode::NO_CONTENT
+            }),
+        )
+    }
+
+    fn cached() -> Settings {
+        Settings {
+            cache_enabled: true,
+            ..test_settings()
+        }
+    }
+
+    fn metadata(version: u64) -> Value {
+        json!({
+            "created_time" : "2024-05-01T09:30:00Z",
+            "deletion_time" : "",
+            "destroyed" : false,
+            "version" : version,
+        })
+    }
+
+    #[tokio::test]
+    async fn reads_a_kv_v2_secret() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            get(|| async {
+                vault_response(json!({
+                    "data" : { "password" : "hunter2" },
+                    "metadata" : metadata(3),
+                }))
+            }),
+        );
+
+        let (status, body) = read(vault, "/secret/app/db").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn missing_secret_is_not_found() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/missing",
+            get(|| async { (StatusCode::NOT_FOUND, Json(json!({ "errors" : [] }))) }),
+        );
+
+        let (status, body) = read(vault, "/secret/app/missing").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn vault_refusing_the_read_is_a_bad_gateway() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            get(|| async {
+                (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({ "errors" : ["permission denied"] })),
+                )
+            }),
+        );
+
+        let (status, body) = read(vault, "/secret/app/db").await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body["error"],
+            json!({ "code" : "vault_unauthorized", "message" : "Vault returned 403: permission denied" })
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_a_secret() {
+        let written = Arc::new(Mutex::new(None));
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            post({
+                let written = written.clone();
+                move |body: String| async move {
+                    *written.lock().unwrap() = serde_json::from_str::<Value>(&body).ok();
+                    vault_response(metadata(1))
+                }
+            }),
+        );
+        let router = router(vault, test_settings()).await;
+
+        let (status, body) = send(router, put("/secret/app/db", r#"{"password":"hunter2"}"#)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"], 1);
+        assert_eq!(
+            written.lock().unwrap().take().unwrap(),
+            json!({ "data" : { "password" : "hunter2" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_secret_is_rejected() {
+        let settings = Settings {
+            max_secret_bytes: 16,
+            ..test_settings()
+        };
+        // The body is rejected before Vault is asked, so the fake has no routes
+        let router = router(Router::new(), settings).await;
+
+        let body = json!({ "password" : "x".repeat(64) }).to_string();
+        let (status, body) = send(router, put("/secret/app/db", body)).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn non_object_secret_is_rejected() {
+        let router = router(Router::new(), test_settings()).await;
+
+        let (status, body) = send(router, put("/secret/app/db", "[1, 2]")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_request");
+    }
+
+    fn delete(uri: &str) -> Request<Body> {
+        Request::delete(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn soft_deletes_the_latest_version() {
+        let calls = Arc::default();
+        let router = router(recording(Arc::clone(&calls)), test_settings()).await;
+
+        let (status, _) = send(router, delete("/secret/app/db")).await;
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(*calls.lock().unwrap(), ["DELETE /v1/secret/data/app/db"]);
+    }
+
+    #[tokio::test]
+    async fn destroys_every_version() {
+        let calls = Arc::default();
+        let router = router(recording(Arc::clone(&calls)), test_settings()).await;
+
+        let (status, _) = send(router, delete("/secret/app/db?destroy=true")).await;
+
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["DELETE /v1/secret/metadata/app/db"]
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_delete_is_forbidden() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            axum::routing::delete(|| async {
+                (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({ "errors" : ["permission denied"] })),
+                )
+            }),
+        );
+        let router = router(vault, test_settings()).await;
+
+        let (status, body) = send(router, delete("/secret/app/db")).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "forbidden");
+    }
+
+    #[tokio::test]
+    async fn second_read_within_the_ttl_is_served_from_the_cache() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let router = router(serving_app_db(reads.clone()), cached()).await;
+
+        for _ in 0..2 {
+            let (status, body) = send(router.clone(), get_request("/secret/app/db")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, json!({ "password" : "hunter2" }));
+        }
+
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn delete_evicts_the_cached_secret() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let router = router(serving_app_db(reads.clone()), cached()).await;
+        send(router.clone(), get_request("/secret/app/db")).await;
+
+        let (status, _) = send(router.clone(), delete("/secret/app/db")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(router, get_request("/secret/app/db")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn batch_reports_failures_per_path() {
+        let vault = Router::new()
+            .route(
+                "/v1/secret/data/app/db",
+                get(|| async {
+                    vault_response(json!({
+                        "data" : { "password" : "hunter2" },
+                        "metadata" : metadata(1),
+                    }))
+                }),
+            )
+            .route(
+                "/v1/secret/data/app/denied",
+                get(|| async {
+                    (
+                        StatusCode::FORBIDDEN,
+                        Json(json!({ "errors" : ["permission denied"] })),
+                    )
+                }),
+            )
+            .fallback(|| async { (StatusCode::NOT_FOUND, Json(json!({ "errors" : [] }))) });
+        let request = Request::post("/secrets/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({ "paths" : ["app/db", "app/missing", "app/denied"] }).to_string(),
+            ))
+            .unwrap();
+
+        let (status, body) = send(router(vault, test_settings()).await, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["results"],
+            json!({ "app/db" : { "password" : "hunter2" } })
+        );
+        assert_eq!(body["errors"]["app/missing"]["code"], "not_found");
+        assert_eq!(body["errors"]["app/denied"]["code"], "vault_unauthorized");
+    }
+
+    #[tokio::test]
+    async fn lists_the_keys_under_a_prefix() {
+        let vault = Router::new()
+            .route(
+                "/v1/secret/metadata/app",
+                axum::routing::any(|| async { vault_response(json!({ "keys" : ["api/", "db"] })) }),
+            )
+            .fallback(|| async { (StatusCode::NOT_FOUND, Json(json!({ "errors" : [] }))) });
+        let router = router(vault, test_settings()).await;
+
+        let (status, body) = send(router.clone(), get_request("/secret/app?list=true")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!(["api/", "db"]));
+
+        let (status, body) = send(router, get_request("/secret/other?list=true")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["message"], "no secrets under other");
+    }
+
+    #[tokio::test]
+    async fn reads_under_one_mount_are_counted_together() {
+        let metrics = crate::metrics::test_handle();
+        let store = MockSecretStore::new()
+            .with("app/db", json!({ "password" : "hunter2" }))
+            .with("app/api", json!({ "key" : "abc" }));
+        let settings = Settings {
+            // A mount of its own, as the recorder is shared with the other tests
+            vault_mount: "counted".into(),
+            access_metric_prefixes: vec!["app".into()],
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, Arc::new(store)));
+
+        for uri in ["/secret/app/db", "/secret/app/api"] {
+            assert_eq!(
+                send(router.clone(), get_request(uri)).await.0,
+                StatusCode::OK
+            );
+        }
+
+        let rendered = metrics.render();
+        let line = rendered
+            .lines()
+            .find(|line| {
+                line.starts_with("secret_reads_by_prefix_total{") && line.contains("\"counted\"")
+            })
+            .unwrap_or_else(|| panic!("no counter for the mount in {}", rendered));
+        assert!(line.contains("prefix=\"app\""), "{}", line);
+        assert!(line.ends_with(" 2"), "{}", line);
+    }
+
+    /// Collects the spans that end.
+    #[derive(Debug, Clone, Default)]
+    struct Spans(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Spans {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn read_span_is_named_for_the_trace_backend() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let spans = Spans::default();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("spot-tests")));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let router = app(AppState::for_tests(test_settings(), Arc::new(store)));
+
+        let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        assert_eq!(send(router, request).await.0, StatusCode::OK);
+        provider.force_flush();
+
+        let spans = spans.0.lock().unwrap();
+        let read = spans
+            .iter()
+            .find(|span| span.name == "vault.read")
+            .unwrap_or_else(|| panic!("no vault.read span in {:?}", spans));
+        assert_eq!(
+            read.attributes.get(&Key::new("vault.path")),
+            Some(&"app".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn wrapped_read_returns_a_token_instead_of_the_secret() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        let router = app(AppState::for_tests(test_settings(), store.clone()));
+
+        let request = Request::get("/secret/app/db?wrap_ttl=60s")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router.clone(), request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["wrap_info"]["token"], "hvs.wrapped");
+        assert_eq!(body["wrap_info"]["ttl"], 60);
+        assert!(!body.to_string().contains("hunter2"));
+        assert_eq!(store.calls(), ["read_wrapped secret/app/db"]);
+
+        let request = Request::get("/secret/app/db?wrap_ttl=soon")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(router, request).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    /// The status, content type and body of reading `path` with `accept`.
+    async fn read_as(path: &str, accept: &str) -> (StatusCode, String, axum::body::Bytes) {
+        let store = MockSecretStore::new()
+            .with("app/db", json!({ "DB_PASSWORD" : "it's-hunter2" }))
+            .with("app/nested", json!({ "db" : { "password" : "hunter2" } }));
+        let router = app(AppState::for_tests(test_settings(), Arc::new(store)));
+        let request = Request::get(&format!("/secret/{}", path))
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+
+        let response = tower::ServiceExt::oneshot(router, request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        (
+            status,
+            content_type,
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn json_is_returned_by_default() {
+        let (status, content_type, body) = read_as("app/db", "application/json").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/json");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "DB_PASSWORD" : "it's-hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn msgpack_decodes_to_the_same_secret() {
+        let (status, content_type, body) = read_as("app/nested", "application/msgpack").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/msgpack");
+        let secret: Value = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(secret, json!({ "db" : { "password" : "hunter2" } }));
+    }
+
+    #[tokio::test]
+    async fn flat_secret_is_returned_as_a_dotenv_file() {
+        let (status, content_type, body) = read_as("app/db", "text/x-dotenv").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "DB_PASSWORD='it'\\''s-hunter2'\n");
+    }
+
+    #[tokio::test]
+    async fn nested_secret_is_not_acceptable_as_dotenv() {
+        let (status, _, body) = read_as("app/nested", "text/plain").await;
+
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "not_acceptable");
+    }
+
+    #[tokio::test]
+    async fn listings_and_wrapped_reads_are_not_acceptable_as_dotenv() {
+        for path in ["app?list=true", "app/db?wrap_ttl=60s"] {
+            let (status, _, body) = read_as(path, "text/plain").await;
+
+            assert_eq!(status, StatusCode::NOT_ACCEPTABLE, "{}", path);
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"]["code"], "not_acceptable");
+        }
+    }
+
+    #[test]
+    fn paths_are_validated() {
+        let settings = Settings {
+            secret_path_max_depth: 3,
+            ..test_settings()
+        };
+        let state = AppState::for_tests(settings, Arc::new(MockSecretStore::new()));
+        let rejected = |path: &str| match super::validate_path(&state, path) {
+            Err(crate::error::AppError::BadRequest(message)) => message,
+            other => panic!("{:?} was not rejected: {:?}", path, other.map(|_| ())),
+        };
+
+        assert!(super::validate_path(&state, "team/app/db").is_ok());
+        assert!(rejected("team/app/db/replica").contains("more than 3 segments"));
+        assert!(rejected("app/d b").contains("characters that are not allowed"));
+        assert!(rejected("app/../sys").contains("must not be . or .."));
+        assert!(rejected("app//db").contains("must not be empty"));
+        assert!(rejected("app/db/").contains("must not be empty"));
+    }
+
+    #[tokio::test]
+    async fn reads_from_an_allowed_mount() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        let settings = Settings {
+            allowed_mounts: vec!["secret".into(), "kv".into()],
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, store.clone()));
+
+        let request = Request::get("/secret/app/db")
+            .header("x-vault-mount", "kv")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(store.calls(), ["read kv/app/db"]);
+    }
+
+    #[tokio::test]
+    async fn mount_that_is_not_allowed_is_forbidden() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        let settings = Settings {
+            allowed_mounts: vec!["secret".into()],
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, store.clone()));
+
+        let request = Request::get("/secret/app/db")
+            .header("x-vault-mount", "other")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "forbidden");
+        assert_eq!(store.reads(), 0);
+    }
+
+    #[tokio::test]
+    async fn mount_outside_the_vault_url_is_rejected() {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let router = app(AppState::for_tests(test_settings(), Arc::new(store)));
+
+        let request = Request::get("/secret/app/db")
+            .header("x-vault-mount", "secret/../sys")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn default_mount_policy_applies_without_allowed_mounts() {
+        for (policy, expected) in [
+            (MountPolicy::Allow, StatusCode::OK),
+            (MountPolicy::Deny, StatusCode::FORBIDDEN),
+        ] {
+            let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+            let settings = Settings {
+                default_mount_policy: policy,
+                ..test_settings()
+            };
+            let router = app(AppState::for_tests(settings, Arc::new(store)));
+
+            let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+            let (status, _) = send(router, request).await;
+
+            assert_eq!(status, expected, "{:?}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_an_older_version() {
+        let store =
+            Arc::new(MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" })));
+        store.insert("app/db", json!({ "password" : "correct-horse" }));
+        let router = app(AppState::for_tests(test_settings(), store));
+        let read = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let (status, body) = send(router.clone(), read("/secret/app/db?version=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+
+        let (status, body) = send(router.clone(), read("/secret/app/db")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "correct-horse" }));
+
+        let (status, body) = send(router.clone(), read("/secret/app/db?version=3")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["message"], "secret app/db has no version 3");
+
+        let (status, _) = send(router, read("/secret/app/db?version=0")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    async fn read_fields(policy: MissingFieldPolicy, fields: &str) -> (StatusCode, Value) {
+        let store = MockSecretStore::new().with(
+            "app/db",
+            json!({ "username" : "app", "password" : "hunter2", "host" : "db.internal" }),
+        );
+        let settings = Settings {
+            missing_field_policy: policy,
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, Arc::new(store)));
+        let uri = format!("/secret/app/db?fields={}", fields);
+
+        send(router, Request::get(&uri).body(Body::empty()).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn returns_only_the_requested_fields() {
+        let (status, body) = read_fields(MissingFieldPolicy::Reject, "username,password").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "username" : "app", "password" : "hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn missing_field_is_rejected_unless_omitted() {
+        let (status, body) = read_fields(MissingFieldPolicy::Reject, "username,port").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "missing_fields");
+        assert_eq!(body["error"]["missing"], json!(["port"]));
+
+        let (status, body) = read_fields(MissingFieldPolicy::Omit, "username,port").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "username" : "app" }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_reads_share_one_store_read() {
+        let store = Arc::new(
+            MockSecretStore::new()
+                .with("app/db", json!({ "password" : "hunter2" }))
+                .delayed(std::time::Duration::from_secs(1)),
+        );
+        let state = AppState::for_tests(test_settings(), store.clone());
+
+        let reads = (0..10).map(|_| super::fetch(&state, None, "secret", "app/db"));
+        let results = futures::future::join_all(reads).await;
+
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(store.reads(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_in_flight_during_a_write_does_not_cache_the_old_value() {
+        let store = Arc::new(
+            MockSecretStore::new()
+                .with("app/db", json!({ "password" : "old" }))
+                .delayed(std::time::Duration::from_secs(1)),
+        );
+        let state = AppState::for_tests(cached(), store.clone());
+
+        let read = tokio::spawn({
+            let state = state.clone();
+            async move { super::fetch(&state, None, "secret", "app/db").await }
+        });
+        // The read has started, and is waiting for its response
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let new = json!({ "password" : "new" });
+        let Json(written) = super::store(&state, None, "secret", "app/db", Ok(Json(new.clone())))
+            .await
+            .unwrap();
+        assert_eq!(written["version"], 2);
+        assert_eq!(read.await.unwrap().unwrap(), json!({ "password" : "old" }));
+
+        let read = super::fetch(&state, None, "secret", "app/db")
+            .await
+            .unwrap();
+        assert_eq!(read, new);
+        assert_eq!(store.reads(), 2);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::mtls;
+use crate::settings::{ListenAddr, Settings};
+use anyhow::Context;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{AddrIncomingConfig, HttpConfig};
+use hyper::server::{accept::Accept, Builder, Server};
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// How often the remaining in-flight requests are logged while draining.
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Serves `app` until `shutdown` is cancelled, over TLS when configured, requiring client
+/// certificates when `tls_client_ca_path` is set too.
+///
+/// After cancellation, in-flight requests tracked by `requests` get up to
+/// `request_drain_timeout_secs` to complete before their connections are dropped, with their
+/// number logged every second meanwhile.
+pub async fn serve(
+    app: Router,
+    settings: &Settings,
+    requests: TaskTracker,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let server = run(app, settings, shutdown.clone());
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => return result,
+        _ = shutdown.cancelled() => {}
+    }
+
+    let timeout = Duration::from_secs(settings.request_drain_timeout_secs);
+    tracing::info!(
+        "waiting up to {}s for {} in-flight requests",
+        timeout.as_secs(),
+        requests.len()
+    );
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut progress = tokio::time::interval_at(
+        tokio::time::Instant::now() + DRAIN_PROGRESS_INTERVAL,
+        DRAIN_PROGRESS_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            result = &mut server => return result,
+            _ = &mut deadline => {
+                tracing::warn!(
+                    "{} requests still in flight after {}s, dropping their connections",
+                    requests.len(),
+                    timeout.as_secs()
+                );
+                return Ok(());
+            }
+            _ = progress.tick() => {
+                if !requests.is_empty() {
+                    tracing::info!("draining, {} requests still in flight", requests.len());
+                }
+            }
+        }
+    }
+}
+
+async fn run(app: Router, settings: &Settings, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let addr = match &settings.listen_addr {
+        ListenAddr::Tcp(addr) => *addr,
+        ListenAddr::Unix(path) => return run_unix(app, path, settings, shutdown).await,
+    };
+
+    match (&settings.tls_cert_path, &settings.tls_key_path) {
+        (Some(cert), Some(key)) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+
+            if let Some(client_ca) = &settings.tls_client_ca_path {
+                let config = mtls::config(cert, key, client_ca).with_context(|| {
+                    format!(
+                        "failed to load TLS certificate {}, key {} and client CA {}",
+                        cert.display(),
+                        key.display(),
+                        client_ca.display()
+                    )
+                })?;
+                tracing::warn!("listening on {} (mutual TLS)", addr);
+                axum_server::bind(addr)
+                    .acceptor(mtls::Acceptor::new(config))
+                    .http_config(http_config(settings))
+                    .addr_incoming_config(incoming_config(settings))
+                    .handle(handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await?;
+                return Ok(());
+            }
+
+            let config = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to load TLS certificate {} and key {}",
+                        cert.display(),
+                        key.display()
+                    )
+                })?;
+            tracing::warn!("listening on {} (TLS)", addr);
+            axum_server::bind_rustls(addr, config)
+                .http_config(http_config(settings))
+                .addr_incoming_config(incoming_config(settings))
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        _ => {
+            tracing::warn!(
+                "listening on {}{}",
+                addr,
+                if settings.h2c_enabled { " (h2c)" } else { "" }
+            );
+            let builder = axum::Server::bind(&addr)
+                .tcp_keepalive(settings.tcp_keepalive_secs.map(Duration::from_secs));
+            tune(builder, settings)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves on a Unix socket at `path`, replacing a stale socket left by an earlier run and
+/// removing it again once stopped.
+///
+/// Requests carry no peer address, so [`crate::client_ip::ClientIp`] is not set for them.
+async fn run_unix(
+    app: Router,
+    path: &Path,
+    settings: &Settings,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind to {}", path.display()))?;
+    std::fs::set_permissions(
+        path,
+        std::fs::Permissions::from_mode(settings.unix_socket_mode),
+    )
+    .with_context(|| format!("failed to set the permissions of {}", path.display()))?;
+
+    tracing::warn!("listening on {}", settings.listen_addr);
+    let result = tune(Server::builder(UnixIncoming(listener)), settings)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await;
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!("failed to remove socket {}: {}", path.display(), e);
+    }
+    Ok(result?)
+}
+
+struct UnixIncoming(UnixListener);
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<UnixStream, std::io::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
+
+/// Applies the protocol, keep-alive and timeout settings to the plain HTTP server.
+fn tune<I>(builder: Builder<I>, settings: &Settings) -> Builder<I> {
+    let mut builder = builder
+        .http1_only(!settings.h2c_enabled)
+        .http1_keepalive(settings.http1_keepalive_enabled)
+        .http2_keep_alive_interval(
+            settings
+                .http2_keepalive_interval_secs
+                .map(Duration::from_secs),
+        )
+        .http2_keep_alive_timeout(Duration::from_secs(settings.http2_keepalive_timeout_secs))
+        .http2_max_concurrent_streams(settings.http2_max_concurrent_streams);
+    if let Some(timeout) = settings.header_read_timeout_secs {
+        builder = builder.http1_header_read_timeout(Duration::from_secs(timeout));
+    }
+    builder
+}
+
+/// The same settings as [`tune`], for the TLS server.
+fn http_config(settings: &Settings) -> HttpConfig {
+    let mut config = HttpConfig::new();
+    config
+        .http1_keep_alive(settings.http1_keepalive_enabled)
+        .http2_keep_alive_interval(
+            settings
+                .http2_keepalive_interval_secs
+                .map(Duration::from_secs),
+        )
+        .http2_keep_alive_timeout(Duration::from_secs(settings.http2_keepalive_timeout_secs))
+        .http2_max_concurrent_streams(settings.http2_max_concurrent_streams);
+    if let Some(timeout) = settings.header_read_timeout_secs {
+        config.http1_header_read_timeout(Duration::from_secs(timeout));
+    }
+    config.build()
+}
+
+fn incoming_config(settings: &Settings) -> AddrIncomingConfig {
+    AddrIncomingConfig::new()
+        .tcp_keepalive(settings.tcp_keepalive_secs.map(Duration::from_secs))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, fake_vault, test_settings, vault_response, AppState};
+    use axum::routing::get;
+    use hyper::{body::Body, Request, StatusCode};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    fn tuned() -> Settings {
+        Settings {
+            http1_keepalive_enabled: false,
+            header_read_timeout_secs: Some(5),
+            http2_keepalive_interval_secs: Some(30),
+            http2_keepalive_timeout_secs: 7,
+            http2_max_concurrent_streams: Some(64),
+            ..Settings::default()
+        }
+    }
+
+    /// Neither builder exposes its options, so check the fields of their debug output.
+    fn assert_tuned(debug: &str) {
+        for field in [
+            "h1_keep_alive: false",
+            "h1_header_read_timeout: Some(5s)",
+            "keep_alive_interval: Some(30s)",
+            "keep_alive_timeout: 7s",
+            "max_concurrent_streams: Some(64)",
+        ] {
+            assert!(debug.contains(field), "{} not in {}", field, debug);
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_server_builder_is_tuned() {
+        let builder = hyper::Server::bind(&free_addr());
+        assert_tuned(&format!("{:?}", tune(builder, &tuned())));
+    }
+
+    #[test]
+    fn tls_server_config_is_tuned() {
+        assert_tuned(&format!("{:?}", http_config(&tuned())));
+    }
+
+    /// Serves `settings` in the background until the returned token is cancelled.
+    fn spawn(
+        settings: Settings,
+    ) -> (
+        CancellationToken,
+        AppState,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let state = AppState::against_vault(settings);
+        let settings = state.settings.load_full();
+        let router = app(state.clone());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn({
+            let (requests, shutdown) = (state.requests.clone(), shutdown.clone());
+            async move { serve(router, &settings, requests, shutdown).await }
+        });
+        (shutdown, state, server)
+    }
+
+    /// A local address that nothing listens on.
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn connect(addr: SocketAddr) -> tokio::net::TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = tokio::net::TcpStream::connect(addr).await {
+                return stream;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("nothing listening on {}", addr);
+    }
+
+    /// A Vault read of a KV v2 secret that notifies `arrived`, then waits for `release`.
+    fn held_read(arrived: Arc<Notify>, release: Arc<Notify>) -> axum::routing::MethodRouter {
+        get(move || async move {
+            arrived.notify_one();
+            release.notified().await;
+            vault_response(json!({
+                "data" : { "password" : "hunter2" },
+                "metadata" : {
+                    "created_time" : "2024-05-01T09:30:00Z",
+                    "deletion_time" : "",
+                    "destroyed" : false,
+                    "version" : 1,
+                },
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn slow_request_completes_during_shutdown() {
+        // Vault holds the read until released, so the request is in flight when shutdown starts
+        let (arrived, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let vault = fake_vault(axum::Router::new().route(
+            "/v1/secret/data/app/db",
+            held_read(arrived.clone(), release.clone()),
+        ))
+        .await;
+        let addr = free_addr();
+        let settings = Settings {
+            listen_addr: ListenAddr::Tcp(addr),
+            vault_address: vault,
+            request_drain_timeout_secs: 60,
+            ..test_settings()
+        };
+        let (shutdown, state, server) = spawn(settings);
+
+        let (mut sender, connection) = hyper::client::conn::handshake(connect(addr).await)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        let response = tokio::spawn(sender.send_request(request));
+        arrived.notified().await;
+        assert_eq!(state.requests.len(), 1);
+
+        shutdown.cancel();
+        // The listener closes once the server has seen the signal
+        while tokio::net::TcpStream::connect(addr).await.is_ok() {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+
+        assert_eq!(response.await.unwrap().unwrap().status(), StatusCode::OK);
+        server.await.unwrap().unwrap();
+        assert!(state.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn serves_health_over_a_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("spot-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("spot.sock");
+        let settings = Settings {
+            listen_addr: ListenAddr::Unix(socket.clone()),
+            ..test_settings()
+        };
+        let (shutdown, _, server) = spawn(settings);
+
+        let mut stream = UnixStream::connect(&socket).await;
+        for _ in 0..100 {
+            if stream.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            stream = UnixStream::connect(&socket).await;
+        }
+        let (mut sender, connection) = hyper::client::conn::handshake(stream.unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/health").body(Body::empty()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(sender);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn h2c_request_gets_an_http2_response() {
+        let addr = free_addr();
+        let settings = Settings {
+            listen_addr: ListenAddr::Tcp(addr),
+            h2c_enabled: true,
+            ..test_settings()
+        };
+        let (shutdown, _, server) = spawn(settings);
+
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake(connect(addr).await)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get(format!("http://{}/health", addr))
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        drop(sender);
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_logs_the_requests_left_in_flight() {
+        let logs = crate::Buffer::default();
+        let _default = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer({
+                    let logs = logs.clone();
+                    move || logs.clone()
+                })
+                .with_ansi(false)
+                .finish(),
+        );
+        // Vault holds each read until it is released, so both requests are in flight at shutdown
+        let held = [(); 2].map(|_| (Arc::new(Notify::new()), Arc::new(Notify::new())));
+        let vault = fake_vault(
+            axum::Router::new()
+                .route(
+                    "/v1/secret/data/app/db",
+                    held_read(held[0].0.clone(), held[0].1.clone()),
+                )
+                .route(
+                    "/v1/secret/data/app/api",
+                    held_read(held[1].0.clone(), held[1].1.clone()),
+                ),
+        )
+        .await;
+        let addr = free_addr();
+        let settings = Settings {
+            listen_addr: ListenAddr::Tcp(addr),
+            vault_address: vault,
+            request_drain_timeout_secs: 60,
+            ..test_settings()
+        };
+        let (shutdown, state, server) = spawn(settings);
+
+        let mut responses = Vec::new();
+        for (path, (arrived, _)) in ["/secret/app/db", "/secret/app/api"].iter().zip(&held) {
+            let (mut sender, connection) = hyper::client::conn::handshake(connect(addr).await)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let request = Request::get(*path).body(Body::empty()).unwrap();
+            responses.push(tokio::spawn(sender.send_request(request)));
+            arrived.notified().await;
+        }
+        assert_eq!(state.requests.len(), 2);
+        shutdown.cancel();
+        while tokio::net::TcpStream::connect(addr).await.is_ok() {
+            tokio::task::yield_now().await;
+        }
+
+        // The first completes, and the next progress line counts the one left
+        held[0].1.notify_one();
+        let first = responses.remove(0).await.unwrap().unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        while !logs
+            .contents()
+            .contains("draining, 1 requests still in flight")
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        held[1].1.notify_one();
+
+        let second = responses.remove(0).await.unwrap().unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        server.await.unwrap().unwrap();
+        let logs = logs.contents();
+        let waiting = logs.find("for 2 in-flight requests").expect(&logs);
+        let draining = logs
+            .find("draining, 1 requests still in flight")
+            .expect(&logs);
+        assert!(waiting < draining, "{}", logs);
+    }
+
+    /// Certificates issued by `ca.pem` to `localhost` and to a client `CN=billing`, and a
+    /// self-signed `untrusted.pem` client certificate.
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/mtls")
+            .join(name)
+    }
+
+    fn read_pem(name: &str) -> Vec<Vec<u8>> {
+        let pem = std::fs::read(fixture(name)).unwrap();
+        if name.ends_with("-key.pem") {
+            rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap()
+        } else {
+            rustls_pemfile::certs(&mut pem.as_slice()).unwrap()
+        }
+    }
+
+    /// Sends `GET /health` over TLS, presenting the client certificate named `client`, if any.
+    async fn health_over_tls(addr: SocketAddr, client: Option<&str>) -> anyhow::Result<StatusCode> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in read_pem("ca.pem") {
+            roots.add(&rustls::Certificate(cert))?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some(client) => config.with_client_auth_cert(
+                read_pem(&format!("{}.pem", client))
+                    .into_iter()
+                    .map(rustls::Certificate)
+                    .collect(),
+                rustls::PrivateKey(read_pem(&format!("{}-key.pem", client)).remove(0)),
+            )?,
+            None => config.with_no_client_auth(),
+        };
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into()?, connect(addr).await)
+            .await?;
+
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        let request = Request::get("/health").body(Body::empty())?;
+        Ok(sender.send_request(request).await?.status())
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_requires_a_trusted_client_certificate() {
+        let addr = free_addr();
+        let settings = Settings {
+            listen_addr: ListenAddr::Tcp(addr),
+            tls_cert_path: Some(fixture("server.pem")),
+            tls_key_path: Some(fixture("server-key.pem")),
+            tls_client_ca_path: Some(fixture("ca.pem")),
+            ..test_settings()
+        };
+        let (shutdown, _, server) = spawn(settings);
+
+        assert_eq!(
+            health_over_tls(addr, Some("client")).await.unwrap(),
+            StatusCode::OK
+        );
+        assert!(health_over_tls(addr, None).await.is_err());
+        assert!(health_over_tls(addr, Some("untrusted")).await.is_err());
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
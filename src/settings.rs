@@ -0,0 +1,997 @@
+use config::{Config, ConfigError, Environment, File, Source};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Fields picked up by [`Settings::reloaded`]; everything else needs a restart.
+const RELOADABLE_FIELDS: &[&str] = &[
+    "allowed_mounts",
+    "default_mount_policy",
+    "log_filter",
+    "missing_field_policy",
+    "rate_limit",
+    "request_timeout_secs",
+    "shutdown_grace_period_secs",
+    "vault_address",
+];
+
+/// Fields masked by [`Settings::redacted`], wherever they appear.
+const SENSITIVE_FIELDS: &[&str] = &[
+    "vault_token",
+    "token",
+    "secret_id",
+    "tls_key_path",
+    "api_token",
+];
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum VaultAuth {
+    Token {
+        token: String,
+    },
+    AppRole {
+        role_id: String,
+        secret_id: String,
+        #[serde(default = "default_approle_mount")]
+        mount: String,
+    },
+    Kubernetes {
+        role: String,
+        #[serde(default = "default_kubernetes_mount")]
+        mount: String,
+        /// Read again on every login, as the service-account token rotates.
+        #[serde(default = "default_kubernetes_token_path")]
+        token_path: PathBuf,
+    },
+}
+
+fn default_approle_mount() -> String {
+    "approle".into()
+}
+
+fn default_kubernetes_mount() -> String {
+    "kubernetes".into()
+}
+
+fn default_kubernetes_token_path() -> PathBuf {
+    "/var/run/secrets/kubernetes.io/serviceaccount/token".into()
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TracingExporter {
+    #[default]
+    Datadog,
+    Otlp {
+        endpoint: String,
+    },
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Pretty,
+    Compact,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub per_second: NonZeroU32,
+    pub burst: NonZeroU32,
+    /// Clients are keyed on this header when present, otherwise on client IP.
+    pub key_header: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cors {
+    /// Origins allowed to call spot, or `"*"` for any.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub allowed_origins: Vec<String>,
+    #[serde(
+        default = "default_cors_methods",
+        deserialize_with = "deserialize_list"
+    )]
+    pub allowed_methods: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".into()]
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtAuth {
+    pub jwks_url: String,
+    pub issuer: String,
+    pub audience: String,
+    #[serde(default = "default_jwks_refresh_interval")]
+    pub refresh_interval_secs: u64,
+}
+
+fn default_jwks_refresh_interval() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CircuitBreaker {
+    /// Consecutive failed Vault calls that open the circuit.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long the open circuit fails calls before letting one through to probe Vault.
+    #[serde(default = "default_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+/// Decrypts `enc:` config values, see [`crate::transit::decrypt`].
+#[derive(Deserialize, Serialize)]
+pub struct Transit {
+    pub key: String,
+    #[serde(default = "default_transit_mount")]
+    pub mount: String,
+    /// Bootstrap token allowed to decrypt with `key`; defaults to `vault_token`.
+    pub token: Option<String>,
+}
+
+fn default_transit_mount() -> String {
+    "transit".into()
+}
+
+/// Where [`crate::audit`] writes its records, one JSON object per line.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AuditSink {
+    Stdout,
+    /// Opened for appending, and created if missing.
+    File {
+        path: PathBuf,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    pub vault_address: String,
+    pub vault_token: Option<String>,
+    /// Falls back to token auth with `vault_token` when unset.
+    pub vault_auth: Option<VaultAuth>,
+    /// Transit key for config values written as `enc:<ciphertext>`.
+    pub transit: Option<Transit>,
+    /// Overrides renewing the token at half its TTL.
+    pub vault_renew_interval_secs: Option<u64>,
+    /// Startup gives up after this many failed connection attempts.
+    pub vault_connect_attempts: u32,
+    /// Start anyway when Vault is unreachable, serving 503 on secret routes until it recovers.
+    pub vault_fail_open: bool,
+    /// Mount of the KV engine, for requests that select none with `X-Vault-Mount`.
+    pub vault_mount: String,
+    /// Mounts secret requests may go to, `vault_mount` or the one selected with
+    /// `X-Vault-Mount`, answering 403 for any other; empty falls back to `default_mount_policy`.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub allowed_mounts: Vec<String>,
+    pub default_mount_policy: MountPolicy,
+    /// Leaves out the secret write and delete routes altogether.
+    pub read_only: bool,
+    /// Idle connections kept open to Vault; unset keeps them all.
+    pub vault_pool_max_idle: Option<usize>,
+    /// Closes pooled Vault connections idle for this long; unset uses reqwest's 90s.
+    pub vault_pool_idle_timeout_secs: Option<u64>,
+    /// Gives up connecting to Vault after this long; unset waits for the OS.
+    pub vault_connect_timeout_secs: Option<u64>,
+    /// KV secrets engine version of `vault_mount`, and of any mount selected per request.
+    pub vault_kv_version: u8,
+    /// Fails Vault calls with 503, without sending them, after repeated failures; unset always
+    /// calls Vault.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Attempts for secret requests failing with a 5xx or connection error; writes, which
+    /// create a new version, are sent at most twice.
+    pub vault_retry_attempts: u32,
+    /// Secret reads stop with a 502 once Vault's response grows larger than this.
+    pub vault_max_response_bytes: usize,
+    /// Vault Enterprise namespace for every request, unless overridden per request.
+    pub vault_namespace: Option<String>,
+    /// Namespaces clients may select with `X-Vault-Namespace`; empty rejects the header.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub vault_namespace_overrides: Vec<String>,
+    /// Largest request body accepted by any route without a tighter limit.
+    pub max_body_bytes: usize,
+    /// Largest request body accepted when writing a secret.
+    pub max_secret_bytes: usize,
+    /// Regex every secret path must match, checked before calling Vault.
+    pub secret_path_pattern: String,
+    /// Secret paths with more segments than this are rejected.
+    pub secret_path_max_depth: usize,
+    /// What reads with `?fields=` do about fields the secret does not have.
+    pub missing_field_policy: MissingFieldPolicy,
+    /// Bearer token clients must present, from `SPOT_API_TOKEN`.
+    pub api_token: Option<String>,
+    /// Validates bearer tokens as JWTs instead of comparing them to `api_token`.
+    pub jwt: Option<JwtAuth>,
+    /// Set to false to serve without authentication, e.g. for local development.
+    pub auth_enabled: bool,
+    /// Maximum number of paths accepted by `POST /secrets/batch`.
+    pub batch_max_paths: usize,
+    /// Maximum number of concurrent Vault reads per batch.
+    pub batch_concurrency: usize,
+    /// Top-level path segments that get their own `prefix` label on `secret_reads_by_prefix_total`;
+    /// reads under any other segment are counted as `other`.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub access_metric_prefixes: Vec<String>,
+    /// Secret paths that must be readable at startup, or spot exits instead of serving.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub required_secrets: Vec<String>,
+    /// How long `GET /admin/secrets/health` waits for each of `required_secrets`.
+    pub secrets_health_timeout_secs: u64,
+    /// Audit sink kept apart from the application logs, so that `log_filter` never drops records.
+    pub audit: AuditSink,
+    /// A TCP `host:port`, or `unix:/path/to/socket`.
+    #[serde(deserialize_with = "deserialize_listen_addr")]
+    pub listen_addr: ListenAddr,
+    /// Permissions of the Unix socket, in octal.
+    #[serde(
+        deserialize_with = "deserialize_mode",
+        serialize_with = "serialize_mode"
+    )]
+    pub unix_socket_mode: u32,
+    /// Peers whose `X-Forwarded-For`/`X-Real-IP` headers are believed, as CIDRs.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub trusted_proxies: Vec<IpNet>,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// PEM CA certificates that TLS clients must present a certificate issued by.
+    pub tls_client_ca_path: Option<PathBuf>,
+    /// Also accepts HTTP/2 with prior knowledge (h2c) without TLS; TLS negotiates HTTP/2 anyway.
+    pub h2c_enabled: bool,
+    /// Set to false to close HTTP/1 connections after each response.
+    pub http1_keepalive_enabled: bool,
+    /// Closes HTTP/1 connections that have not sent complete request headers within this long.
+    pub header_read_timeout_secs: Option<u64>,
+    /// Sends HTTP/2 pings this often on idle connections; unset disables them.
+    pub http2_keepalive_interval_secs: Option<u64>,
+    /// Closes HTTP/2 connections whose pings go unanswered for this long.
+    pub http2_keepalive_timeout_secs: u64,
+    /// Streams a client may open at once on one HTTP/2 connection; unset leaves it unlimited.
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Enables TCP keepalive probes after a connection has been idle this long.
+    pub tcp_keepalive_secs: Option<u64>,
+    pub log_filter: String,
+    /// Defaults to `pretty` on a terminal and `json` otherwise.
+    pub log_format: Option<LogFormat>,
+    pub request_timeout_secs: u64,
+    /// Prefix every route is served under, e.g. `/spot`; empty serves them at the root.
+    pub base_path: String,
+    /// Largest template accepted by `POST /render`.
+    pub render_max_template_bytes: usize,
+    /// `POST /render` gives up on rendering a template after this long.
+    pub render_timeout_secs: u64,
+    /// Liveness probe route, served without authentication.
+    pub health_path: String,
+    /// Reports `draining` instead of `UP` on the liveness probe once shutdown has begun,
+    /// still with a 200.
+    pub health_draining_enabled: bool,
+    /// Readiness probe route.
+    pub ready_path: String,
+    /// Probes within this long of the last readiness check reuse its result.
+    pub readiness_cache_secs: u64,
+    /// Requests beyond this many in flight are rejected with 503.
+    pub max_inflight: usize,
+    pub rate_limit: Option<RateLimit>,
+    /// Browser cross-origin requests are refused when unset.
+    pub cors: Option<Cors>,
+    /// Serves repeated reads from memory for up to `cache_ttl_secs`.
+    pub cache_enabled: bool,
+    pub cache_ttl_secs: u64,
+    /// Maximum number of secrets held in the cache.
+    pub cache_max_capacity: u64,
+    /// Secrets read into the cache at startup, so that the first requests for them are fast.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub preload_paths: Vec<String>,
+    /// Fail startup when one of `preload_paths` cannot be read, instead of logging it.
+    pub preload_required: bool,
+    /// OpenTelemetry metrics are only exported with `otlp`; Vault call metrics are also on
+    /// `/metrics`.
+    pub tracing_exporter: TracingExporter,
+    /// Set to false to start with logging only when the span exporter cannot be installed.
+    pub tracing_required: bool,
+    pub dd_agent_endpoint: Option<String>,
+    pub dd_service_name: Option<String>,
+    pub dd_env: Option<String>,
+    pub metrics_enabled: bool,
+    /// Fail startup, instead of serving without metrics, when the recorder cannot be installed.
+    pub metrics_required: bool,
+    /// How often the Tokio runtime's counters are published as `tokio_*` gauges; unset
+    /// publishes none.
+    pub runtime_metrics_interval_secs: Option<u64>,
+    /// Serves `/debug/profile`; leave off outside of debugging sessions.
+    pub debug_endpoints: bool,
+    /// Reports where each request spent its time in an `X-Timing` response header.
+    pub timing_header_enabled: bool,
+    /// Serves Swagger UI for `/openapi.json` at `/swagger-ui`.
+    pub swagger_ui_enabled: bool,
+    /// Compresses responses with gzip or brotli when the client accepts it.
+    pub compression_enabled: bool,
+    /// How long in-flight requests get to finish after a shutdown signal.
+    pub request_drain_timeout_secs: u64,
+    /// How long background tasks get to finish after a shutdown signal.
+    pub shutdown_grace_period_secs: u64,
+    /// Signals that start a graceful shutdown; the others of SIGTERM, SIGINT and SIGUSR2 keep
+    /// their default action.
+    #[serde(deserialize_with = "deserialize_list")]
+    pub shutdown_signals: Vec<ShutdownSignal>,
+    /// Where each top-level field was set, as reported by `GET /admin/config`.
+    #[serde(skip)]
+    pub sources: BTreeMap<String, ValueSource>,
+}
+
+/// Whether a read with `?fields=` fails with 422 when the secret lacks one of the fields, or
+/// leaves it out of the response.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingFieldPolicy {
+    #[default]
+    Reject,
+    Omit,
+}
+
+/// Whether secret requests may reach a mount when `allowed_mounts` is empty.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueSource {
+    Default,
+    File,
+    Env,
+}
+
+/// SIGHUP, SIGUSR1 and SIGQUIT are left out, being taken by config reloads, log level cycling
+/// and diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownSignal {
+    Term,
+    Int,
+    Usr2,
+}
+
+impl ShutdownSignal {
+    pub const ALL: [ShutdownSignal; 3] = [
+        ShutdownSignal::Term,
+        ShutdownSignal::Int,
+        ShutdownSignal::Usr2,
+    ];
+}
+
+impl std::fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ShutdownSignal::Term => "SIGTERM",
+            ShutdownSignal::Int => "SIGINT",
+            ShutdownSignal::Usr2 => "SIGUSR2",
+        })
+    }
+}
+
+impl FromStr for ShutdownSignal {
+    type Err = String;
+
+    /// Accepts `SIGTERM` or `TERM`, in any case.
+    fn from_str(name: &str) -> Result<Self, String> {
+        let upper = name.trim().to_ascii_uppercase();
+        let bare = upper.strip_prefix("SIG").unwrap_or(&upper);
+        ShutdownSignal::ALL
+            .into_iter()
+            .find(|signal| signal.to_string()[3..] == *bare)
+            .ok_or_else(|| {
+                format!(
+                    "unsupported shutdown signal {:?}, expected SIGTERM, SIGINT or SIGUSR2",
+                    name
+                )
+            })
+    }
+}
+
+impl Serialize for ShutdownSignal {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn deserialize_listen_addr<'de, D>(deserializer: D) -> Result<ListenAddr, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addr = String::deserialize(deserializer)?;
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return Ok(ListenAddr::Unix(path.into()));
+    }
+    addr.parse().map(ListenAddr::Tcp).map_err(|e| {
+        de::Error::custom(format!(
+            "invalid listen address {:?}, expected host:port such as 0.0.0.0:3000 \
+             or unix:/path/to/socket: {}",
+            addr, e
+        ))
+    })
+}
+
+/// Reads file permissions written in octal, e.g. `660` or `0660`.
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| de::Error::custom(format!("invalid octal file mode {:?}", mode)))
+}
+
+fn serialize_mode<S: serde::Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:04o}", mode))
+}
+
+/// Accepts a list, or a comma-separated string as given by environment variables.
+fn deserialize_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        Joined(String),
+        Items(Vec<String>),
+    }
+
+    let items = match List::deserialize(deserializer)? {
+        List::Joined(joined) => joined
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        List::Items(items) => items,
+    };
+    items
+        .iter()
+        .map(|item| {
+            item.parse()
+                .map_err(|e| de::Error::custom(format!("invalid list item {:?}: {}", item, e)))
+        })
+        .collect()
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            vault_address: "https://127.0.0.1:8200".into(),
+            vault_token: None,
+            vault_auth: None,
+            transit: None,
+            vault_renew_interval_secs: None,
+            vault_connect_attempts: 5,
+            vault_fail_open: false,
+            vault_mount: "secret".into(),
+            allowed_mounts: Vec::new(),
+            default_mount_policy: MountPolicy::Allow,
+            read_only: false,
+            vault_pool_max_idle: None,
+            vault_pool_idle_timeout_secs: None,
+            vault_connect_timeout_secs: None,
+            vault_kv_version: 2,
+            vault_retry_attempts: 3,
+            vault_max_response_bytes: 1024 * 1024,
+            circuit_breaker: None,
+            vault_namespace: None,
+            vault_namespace_overrides: Vec::new(),
+            max_body_bytes: 1024 * 1024,
+            max_secret_bytes: 64 * 1024,
+            secret_path_pattern: r"^[\w.@=+:/-]+$".into(),
+            secret_path_max_depth: 16,
+            missing_field_policy: MissingFieldPolicy::Reject,
+            api_token: None,
+            jwt: None,
+            auth_enabled: true,
+            batch_max_paths: 100,
+            batch_concurrency: 8,
+            access_metric_prefixes: Vec::new(),
+            required_secrets: Vec::new(),
+            secrets_health_timeout_secs: 5,
+            audit: AuditSink::Stdout,
+            listen_addr: ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000))),
+            unix_socket_mode: 0o660,
+            trusted_proxies: Vec::new(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            h2c_enabled: false,
+            http1_keepalive_enabled: true,
+            header_read_timeout_secs: None,
+            http2_keepalive_interval_secs: None,
+            http2_keepalive_timeout_secs: 20,
+            http2_max_concurrent_streams: None,
+            tcp_keepalive_secs: None,
+            // otel::tracing carries the request spans that inbound trace context is attached to
+            log_filter: "opentelemetry=debug,otel::tracing=trace,spot=debug,tower_http=debug"
+                .into(),
+            log_format: None,
+            request_timeout_secs: 30,
+            base_path: String::new(),
+            render_max_template_bytes: 64 * 1024,
+            render_timeout_secs: 5,
+            health_path: "/health".into(),
+            health_draining_enabled: false,
+            ready_path: "/ready".into(),
+            readiness_cache_secs: 2,
+            max_inflight: 1024,
+            rate_limit: None,
+            cors: None,
+            cache_enabled: false,
+            cache_ttl_secs: 30,
+            cache_max_capacity: 10_000,
+            preload_paths: Vec::new(),
+            preload_required: false,
+            tracing_exporter: TracingExporter::default(),
+            tracing_required: true,
+            dd_agent_endpoint: None,
+            dd_service_name: None,
+            dd_env: None,
+            metrics_enabled: true,
+            metrics_required: false,
+            runtime_metrics_interval_secs: None,
+            debug_endpoints: false,
+            timing_header_enabled: false,
+            swagger_ui_enabled: false,
+            compression_enabled: true,
+            request_drain_timeout_secs: 20,
+            shutdown_grace_period_secs: 10,
+            shutdown_signals: vec![ShutdownSignal::Term, ShutdownSignal::Int],
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads `config_file`, or `./spot.{toml,yaml,json}` if present, with env overrides on top.
+    pub fn new(config_file: Option<&Path>) -> Result<Self, ConfigError> {
+        let file = match config_file {
+            Some(path) => File::from(path).required(true),
+            None => File::with_name("spot").required(false),
+        };
+        // e.g. `SPOT_VAULT_AUTH__ROLE_ID` sets `vault_auth.role_id`
+        let env = Environment::with_prefix("SPOT")
+            .prefix_separator("_")
+            .separator("__");
+        let mut settings: Settings = Config::builder()
+            .add_source(file.clone())
+            .add_source(env.clone())
+            .build()?
+            .try_deserialize()?;
+        settings.sources = sources(&settings, &file.collect()?, &env.collect()?);
+
+        reqwest::Url::parse(&settings.vault_address).map_err(|e| {
+            ConfigError::Message(format!(
+                "invalid vault_address {:?}: {}",
+                settings.vault_address, e
+            ))
+        })?;
+
+        if matches!(settings.listen_addr, ListenAddr::Unix(_)) && settings.tls_cert_path.is_some() {
+            return Err(ConfigError::Message(
+                "TLS is not supported on a unix: listen_addr".into(),
+            ));
+        }
+        match (&settings.tls_cert_path, &settings.tls_key_path) {
+            (Some(_), None) => {
+                return Err(ConfigError::Message(
+                    "tls_cert_path is set but tls_key_path is not".into(),
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Message(
+                    "tls_key_path is set but tls_cert_path is not".into(),
+                ))
+            }
+            _ => {}
+        }
+        if settings.tls_client_ca_path.is_some() && settings.tls_cert_path.is_none() {
+            return Err(ConfigError::Message(
+                "tls_client_ca_path is set but tls_cert_path is not".into(),
+            ));
+        }
+
+        if !matches!(settings.vault_kv_version, 1 | 2) {
+            return Err(ConfigError::Message(format!(
+                "vault_kv_version must be 1 or 2, not {}",
+                settings.vault_kv_version
+            )));
+        }
+
+        for (name, path) in [
+            ("health_path", &settings.health_path),
+            ("ready_path", &settings.ready_path),
+        ] {
+            if !path.starts_with('/') {
+                return Err(ConfigError::Message(format!(
+                    "{} {:?} must start with /",
+                    name, path
+                )));
+            }
+        }
+        if !settings.base_path.is_empty()
+            && (!settings.base_path.starts_with('/') || settings.base_path.ends_with('/'))
+        {
+            return Err(ConfigError::Message(format!(
+                "base_path {:?} must start with / and not end with one",
+                settings.base_path
+            )));
+        }
+        if settings.health_path == settings.ready_path {
+            return Err(ConfigError::Message(
+                "health_path and ready_path must differ".into(),
+            ));
+        }
+
+        if let Err(e) = regex::Regex::new(&settings.secret_path_pattern) {
+            return Err(ConfigError::Message(format!(
+                "invalid secret_path_pattern: {}",
+                e
+            )));
+        }
+
+        if settings
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.failure_threshold == 0)
+        {
+            return Err(ConfigError::Message(
+                "circuit_breaker.failure_threshold must be at least 1".into(),
+            ));
+        }
+
+        if settings.runtime_metrics_interval_secs == Some(0) {
+            return Err(ConfigError::Message(
+                "runtime_metrics_interval_secs must be greater than 0".into(),
+            ));
+        }
+
+        if settings.shutdown_signals.is_empty() {
+            return Err(ConfigError::Message(
+                "shutdown_signals must name at least one signal".into(),
+            ));
+        }
+
+        if let Some(cors) = &settings.cors {
+            let _ =
+                crate::cors::layer(cors).map_err(|e| ConfigError::Message(format!("{:#}", e)))?;
+        }
+
+        if settings.auth_enabled && settings.api_token.is_none() && settings.jwt.is_none() {
+            return Err(ConfigError::Message(
+                "api_token or jwt is required, set SPOT_API_TOKEN or disable auth_enabled".into(),
+            ));
+        }
+
+        if settings.vault_auth.is_none() {
+            settings.vault_auth = settings
+                .vault_token
+                .clone()
+                .map(|token| VaultAuth::Token { token });
+            let source = settings.sources["vault_token"];
+            settings.sources.insert("vault_auth".into(), source);
+        }
+
+        Ok(settings)
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format.unwrap_or_else(|| {
+            if std::io::stdout().is_terminal() {
+                LogFormat::Pretty
+            } else {
+                LogFormat::Json
+            }
+        })
+    }
+
+    /// Whether `allowed_mounts`, or `default_mount_policy` without any, lets requests reach `mount`.
+    pub fn mount_allowed(&self, mount: &str) -> bool {
+        if self.allowed_mounts.is_empty() {
+            self.default_mount_policy == MountPolicy::Allow
+        } else {
+            self.allowed_mounts.iter().any(|allowed| allowed == mount)
+        }
+    }
+
+    /// Takes the reloadable fields from `new`, warning about any other changes.
+    pub fn reloaded(&self, new: &Settings) -> anyhow::Result<Settings> {
+        let current = serde_json::to_value(self)?;
+        let mut merged = current.clone();
+        if let Value::Object(fields) = serde_json::to_value(new)? {
+            for (key, value) in fields {
+                if RELOADABLE_FIELDS.contains(&key.as_str()) {
+                    merged[&key] = value;
+                } else if current.get(&key) != Some(&value) {
+                    tracing::warn!("{} changed, ignored until restart", key);
+                }
+            }
+        }
+        let mut reloaded: Settings = serde_json::from_value(merged)?;
+        reloaded.sources = self.sources.clone();
+        for key in RELOADABLE_FIELDS {
+            if let Some(source) = new.sources.get(*key) {
+                reloaded.sources.insert(key.to_string(), *source);
+            }
+        }
+        Ok(reloaded)
+    }
+
+    /// The effective settings as JSON with credentials masked.
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).expect("settings serialize to JSON");
+        redact(&mut value);
+        value
+    }
+}
+
+// Debug prints the redacted JSON, so that credentials never reach a log through `{:?}`
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        debug_redacted("Settings", self, f)
+    }
+}
+
+impl std::fmt::Debug for VaultAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        debug_redacted("VaultAuth", self, f)
+    }
+}
+
+impl std::fmt::Debug for Transit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        debug_redacted("Transit", self, f)
+    }
+}
+
+fn debug_redacted(
+    name: &str,
+    value: &impl Serialize,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    let mut value = serde_json::to_value(value).map_err(|_| std::fmt::Error)?;
+    redact(&mut value);
+    write!(f, "{} {}", name, value)
+}
+
+/// The source of every top-level field, with the environment taking precedence over the file.
+fn sources(
+    settings: &Settings,
+    file: &config::Map<String, config::Value>,
+    env: &config::Map<String, config::Value>,
+) -> BTreeMap<String, ValueSource> {
+    let set_in = |values: &config::Map<String, config::Value>, key: &str| {
+        values
+            .keys()
+            .any(|name| name.split('.').next() == Some(key))
+    };
+    let Ok(Value::Object(fields)) = serde_json::to_value(settings) else {
+        return BTreeMap::new();
+    };
+    fields
+        .into_iter()
+        .map(|(key, _)| {
+            let source = if set_in(env, &key) {
+                ValueSource::Env
+            } else if set_in(file, &key) {
+                ValueSource::File
+            } else {
+                ValueSource::Default
+            };
+            (key, source)
+        })
+        .collect()
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SENSITIVE_FIELDS.contains(&key.as_str()) {
+                    if let Some(secret) = value.as_str() {
+                        *value = Value::String(mask(secret));
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Keeps the last few characters of long values so operators can tell them apart.
+fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() >= 16 {
+        format!(
+            "****{}",
+            chars[chars.len() - 4..].iter().collect::<String>()
+        )
+    } else {
+        "****".into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    /// Loads `contents` as the config file `name`, whose extension picks the format.
+    fn load(name: &str, contents: &str) -> Result<Settings, ConfigError> {
+        let dir = std::env::temp_dir().join(format!("spot-settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        Settings::new(Some(&path))
+    }
+
+    fn parse(toml: &str) -> Result<Settings, ConfigError> {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn invalid_listen_address_is_a_descriptive_error() {
+        let error = parse("listen_addr = \"localhost:3000\"\n")
+            .unwrap_err()
+            .to_string();
+
+        assert!(
+            error.contains("invalid listen address \"localhost:3000\""),
+            "{}",
+            error
+        );
+        assert!(error.contains("expected host:port"), "{}", error);
+    }
+
+    #[test]
+    fn redacted_masks_tokens_and_secret_ids() {
+        let settings = Settings {
+            vault_token: Some("hvs.CAESIJ-root-token".into()),
+            vault_auth: Some(VaultAuth::AppRole {
+                role_id: "billing".into(),
+                secret_id: "short-secret".into(),
+                mount: "approle".into(),
+            }),
+            api_token: Some("spot-api-token-value".into()),
+            ..Settings::default()
+        };
+
+        let redacted = settings.redacted();
+
+        // Long values keep their last characters, short ones nothing
+        assert_eq!(redacted["vault_token"], "****oken");
+        assert_eq!(redacted["vault_auth"]["secret_id"], "****");
+        assert_eq!(redacted["vault_auth"]["role_id"], "billing");
+        assert_eq!(redacted["api_token"], "****alue");
+        let text = redacted.to_string();
+        for secret in [
+            "hvs.CAESIJ-root-token",
+            "short-secret",
+            "spot-api-token-value",
+        ] {
+            assert!(!text.contains(secret), "{} in {}", secret, text);
+        }
+    }
+
+    #[test]
+    fn yaml_file_is_loaded_from_an_explicit_path() {
+        let settings = load(
+            "explicit.yaml",
+            "vault_address: https://vault.example:8200\nvault_mount: kv\nauth_enabled: false\n",
+        )
+        .unwrap();
+
+        assert_eq!(settings.vault_address, "https://vault.example:8200");
+        assert_eq!(settings.vault_mount, "kv");
+        assert!(!settings.auth_enabled);
+    }
+
+    #[test]
+    fn shutdown_signals_are_named_with_or_without_the_sig_prefix() {
+        let settings = load(
+            "signals.toml",
+            "auth_enabled = false\nshutdown_signals = \"term, SIGUSR2\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.shutdown_signals,
+            [ShutdownSignal::Term, ShutdownSignal::Usr2]
+        );
+
+        let error = load(
+            "hup.toml",
+            "auth_enabled = false\nshutdown_signals = \"SIGHUP\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("unsupported shutdown signal"), "{}", error);
+
+        let error = load(
+            "none.toml",
+            "auth_enabled = false\nshutdown_signals = \"\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("at least one signal"), "{}", error);
+    }
+
+    #[test]
+    fn debug_output_masks_credentials() {
+        let settings = Settings {
+            vault_token: Some("hvs.root-token-value".into()),
+            vault_auth: Some(VaultAuth::AppRole {
+                role_id: "billing".into(),
+                secret_id: "approle-secret-id".into(),
+                mount: "approle".into(),
+            }),
+            ..Settings::default()
+        };
+
+        let debug = format!("{:?}", settings);
+
+        assert!(!debug.contains("hvs.root-token-value"), "{}", debug);
+        assert!(!debug.contains("approle-secret-id"), "{}", debug);
+        assert!(debug.contains("billing"));
+    }
+}
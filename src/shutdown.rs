@@ -0,0 +1,122 @@
+use crate::settings::ShutdownSignal;
+use crate::AppState;
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use std::time::Duration;
+use tokio::signal::unix::{self, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Cancels `token` once one of `signals` is received.
+///
+/// Only `signals` get a handler, so the others keep their default action.
+pub async fn signal(signals: Vec<ShutdownSignal>, token: CancellationToken) {
+    let mut handlers: Vec<_> = handled(&signals)
+        .into_iter()
+        .map(|(signal, kind)| {
+            let handler = unix::signal(kind)
+                .unwrap_or_else(|e| panic!("failed to install {} handler: {}", signal, e));
+            (signal, handler)
+        })
+        .collect();
+
+    let received =
+        futures::future::select_all(handlers.iter_mut().map(|(signal, handler)| {
+            Box::pin(async move { handler.recv().await.map(|_| *signal) })
+        }));
+    tokio::select! {
+        (Some(signal), _, _) = received => {
+            tracing::warn!("{} received, starting graceful shutdown", signal);
+            token.cancel();
+        }
+        _ = token.cancelled() => {}
+    }
+}
+
+/// The signals to install handlers for, each once.
+fn handled(signals: &[ShutdownSignal]) -> Vec<(ShutdownSignal, SignalKind)> {
+    ShutdownSignal::ALL
+        .into_iter()
+        .filter(|signal| signals.contains(signal))
+        .map(|signal| {
+            let kind = match signal {
+                ShutdownSignal::Term => SignalKind::terminate(),
+                ShutdownSignal::Int => SignalKind::interrupt(),
+                ShutdownSignal::Usr2 => SignalKind::user_defined2(),
+            };
+            (signal, kind)
+        })
+        .collect()
+}
+
+/// Sleeps for `duration`, returning `false` if shutdown was requested first.
+pub async fn sleep(token: &CancellationToken, duration: Duration) -> bool {
+    tokio::select! {
+        _ = token.cancelled() => false,
+        _ = tokio::time::sleep(duration) => true,
+    }
+}
+
+/// Waits for the tracked background tasks to finish, giving up after `grace`.
+pub async fn drain(tasks: TaskTracker, grace: Duration) {
+    tasks.close();
+    if tokio::time::timeout(grace, tasks.wait()).await.is_err() {
+        tracing::warn!(
+            "{} background tasks still running after {}s, forcing exit",
+            tasks.len(),
+            grace.as_secs()
+        );
+    }
+}
+
+/// Counts the request as in flight until its response is ready.
+pub async fn track_request<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let _in_flight = state.requests.token();
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_the_token_ends_a_background_loop() {
+        let token = CancellationToken::new();
+        let tasks = TaskTracker::new();
+        let ticks = tasks.spawn({
+            let token = token.clone();
+            async move {
+                let mut ticks = 0;
+                while sleep(&token, Duration::from_secs(1)).await {
+                    ticks += 1;
+                }
+                ticks
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+
+        token.cancel();
+        drain(tasks.clone(), Duration::from_secs(30)).await;
+
+        assert!(tasks.is_empty());
+        assert_eq!(ticks.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn handles_only_the_configured_signals() {
+        assert_eq!(
+            handled(&[
+                ShutdownSignal::Usr2,
+                ShutdownSignal::Term,
+                ShutdownSignal::Usr2
+            ]),
+            [
+                (ShutdownSignal::Term, SignalKind::terminate()),
+                (ShutdownSignal::Usr2, SignalKind::user_defined2()),
+            ]
+        );
+    }
+}
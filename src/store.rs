@@ -0,0 +1,727 @@
+use crate::settings::Settings;
+use crate::vault;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vaultrs::api::kv1::requests::GetSecretRequest;
+use vaultrs::api::kv1::responses::GetSecretResponse;
+use vaultrs::api::kv2::requests::ReadSecretRequest;
+use vaultrs::api::kv2::responses::ReadSecretResponse;
+use vaultrs::api::kv2::responses::SecretVersionMetadata;
+use vaultrs::api::{EndpointResult, WrapInfo};
+use vaultrs::client::{Client, VaultClient};
+use vaultrs::error::ClientError;
+
+/// Where the secret handlers read and write, so that they do not depend on a live Vault.
+///
+/// `namespace` overrides `vault_namespace` for a single call, and `mount` is the KV engine the
+/// path is below, `vault_mount` unless the request chose another.
+#[async_trait]
+pub trait SecretStore: Send + Sync {
+    async fn read(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Value, ClientError>;
+
+    /// Reads an older version, on engines that keep versions; others answer 404.
+    async fn read_version(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        version: u64,
+    ) -> Result<Value, ClientError>;
+
+    /// Has Vault return a single-use token that unwraps to what `read` would return.
+    async fn read_wrapped(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        ttl: &str,
+    ) -> Result<WrapInfo, ClientError>;
+
+    /// Returns the version created, on engines that keep versions.
+    async fn write(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        secret: &Map<String, Value>,
+    ) -> Result<Option<SecretVersionMetadata>, ClientError>;
+
+    /// Soft-deletes the latest version, which can still be undeleted.
+    async fn delete_latest(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError>;
+
+    /// Permanently removes every version and the metadata.
+    async fn destroy(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError>;
+
+    /// Child keys of a directory, with sub-directories ending in `/`.
+    async fn list(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Vec<String>, ClientError>;
+}
+
+/// The store for the KV engine version mounted at `vault_mount`.
+pub fn for_settings(
+    vault: Arc<ArcSwap<VaultClient>>,
+    settings: Arc<ArcSwap<Settings>>,
+) -> Arc<dyn SecretStore> {
+    let version = settings.load().vault_kv_version;
+    match version {
+        1 => Arc::new(KvV1 { vault, settings }),
+        _ => Arc::new(KvV2 { vault, settings }),
+    }
+}
+
+/// Fails with every path in `paths` that cannot be read, missing or otherwise.
+pub async fn require(store: &dyn SecretStore, mount: &str, paths: &[String]) -> anyhow::Result<()> {
+    let results =
+        futures::future::join_all(paths.iter().map(|path| store.read(None, mount, path))).await;
+    let unreadable: Vec<String> = paths
+        .iter()
+        .zip(results)
+        .filter_map(|(path, result)| match result {
+            Ok(_) => None,
+            Err(ClientError::APIError { code: 404, .. }) => Some(format!("{} (missing)", path)),
+            Err(e) => Some(format!("{} ({})", path, vault::reason(e))),
+        })
+        .collect();
+    if !unreadable.is_empty() {
+        anyhow::bail!(
+            "required secrets are not readable: {}",
+            unreadable.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The current settings, and the current client sending to `namespace`.
+fn connect(
+    vault: &ArcSwap<VaultClient>,
+    settings: &ArcSwap<Settings>,
+    namespace: Option<&str>,
+) -> (Arc<Settings>, vault::Namespaced) {
+    let client = vault::Namespaced::new(vault.load_full(), namespace);
+    (settings.load_full(), client)
+}
+
+/// A KV v1 engine, which keeps a single version and deletes permanently.
+pub struct KvV1 {
+    vault: Arc<ArcSwap<VaultClient>>,
+    settings: Arc<ArcSwap<Settings>>,
+}
+
+#[async_trait]
+impl SecretStore for KvV1 {
+    async fn read(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Value, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv1.get", settings.vault_retry_attempts, || async {
+            let endpoint = GetSecretRequest::builder()
+                .mount(mount)
+                .path(path)
+                .build()
+                .expect("mount and path are set");
+            let response: GetSecretResponse =
+                vault::exec_limited(&client, endpoint, settings.vault_max_response_bytes)
+                    .await?
+                    .parse()?;
+            Ok(response.data)
+        })
+        .await
+    }
+
+    async fn read_version(
+        &self,
+        _namespace: Option<&str>,
+        _mount: &str,
+        _path: &str,
+        _version: u64,
+    ) -> Result<Value, ClientError> {
+        Err(ClientError::APIError {
+            code: 404,
+            errors: vec!["KV v1 mounts keep no versions".into()],
+        })
+    }
+
+    async fn read_wrapped(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        ttl: &str,
+    ) -> Result<WrapInfo, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv1.read_wrapped", settings.vault_retry_attempts, || {
+            let endpoint = GetSecretRequest::builder()
+                .mount(mount)
+                .path(path)
+                .build()
+                .expect("mount and path are set");
+            vault::wrap(&client, endpoint, ttl)
+        })
+        .await
+    }
+
+    async fn write(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        secret: &Map<String, Value>,
+    ) -> Result<Option<SecretVersionMetadata>, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        let data: HashMap<&str, &Value> = secret.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        // Writing the same data again is harmless here, so writes are retried like reads
+        vault::call_with_retry("kv1.set", settings.vault_retry_attempts, || {
+            vaultrs::kv1::set(&client, mount, path, &data)
+        })
+        .await?;
+        Ok(None)
+    }
+
+    async fn delete_latest(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        self.destroy(namespace, mount, path).await
+    }
+
+    async fn destroy(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv1.delete", settings.vault_retry_attempts, || {
+            vaultrs::kv1::delete(&client, mount, path)
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        let response = vault::call_with_retry("kv1.list", settings.vault_retry_attempts, || {
+            vaultrs::kv1::list(&client, mount, path)
+        })
+        .await?;
+        Ok(response.data.keys)
+    }
+}
+
+/// A KV v2 engine, which keeps a version per write.
+pub struct KvV2 {
+    vault: Arc<ArcSwap<VaultClient>>,
+    settings: Arc<ArcSwap<Settings>>,
+}
+
+#[async_trait]
+impl SecretStore for KvV2 {
+    async fn read(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Value, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.read", settings.vault_retry_attempts, || {
+            read_limited(&client, &settings, mount, path, None)
+        })
+        .await
+    }
+
+    async fn read_version(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        version: u64,
+    ) -> Result<Value, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.read_version", settings.vault_retry_attempts, || {
+            read_limited(&client, &settings, mount, path, Some(version))
+        })
+        .await
+    }
+
+    async fn read_wrapped(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        ttl: &str,
+    ) -> Result<WrapInfo, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.read_wrapped", settings.vault_retry_attempts, || {
+            let endpoint = ReadSecretRequest::builder()
+                .mount(mount)
+                .path(path)
+                .build()
+                .expect("mount and path are set");
+            vault::wrap(&client, endpoint, ttl)
+        })
+        .await
+    }
+
+    async fn write(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        secret: &Map<String, Value>,
+    ) -> Result<Option<SecretVersionMetadata>, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.set", settings.vault_retry_attempts.min(2), || {
+            vaultrs::kv2::set(&client, mount, path, secret)
+        })
+        .await
+        .map(Some)
+    }
+
+    async fn delete_latest(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.delete_latest", settings.vault_retry_attempts, || {
+            vaultrs::kv2::delete_latest(&client, mount, path)
+        })
+        .await
+    }
+
+    async fn destroy(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.delete_metadata", settings.vault_retry_attempts, || {
+            vaultrs::kv2::delete_metadata(&client, mount, path)
+        })
+        .await
+    }
+
+    async fn list(
+        &self,
+        namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        let (settings, client) = connect(&self.vault, &self.settings, namespace);
+        vault::call_with_retry("kv2.list", settings.vault_retry_attempts, || {
+            vaultrs::kv2::list(&client, mount, path)
+        })
+        .await
+    }
+}
+
+/// Reads the data of a KV v2 secret, at most `vault_max_response_bytes` of it.
+async fn read_limited(
+    client: &impl Client,
+    settings: &Settings,
+    mount: &str,
+    path: &str,
+    version: Option<u64>,
+) -> Result<Value, ClientError> {
+    let endpoint = ReadSecretRequest::builder()
+        .mount(mount)
+        .path(path)
+        .version(version)
+        .build()
+        .expect("mount and path are set");
+    let response: EndpointResult<ReadSecretResponse> =
+        vault::exec_limited(client, endpoint, settings.vault_max_response_bytes)
+            .await?
+            .wrap()?;
+    response
+        .data
+        .map(|secret| secret.data)
+        .ok_or(ClientError::ResponseDataEmptyError)
+}
+
+/// An in-memory store for tests, which counts the reads that reach it.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockSecretStore {
+    /// Every version written to each path, oldest first, with soft-deleted ones as `None`.
+    secrets: std::sync::Mutex<std::collections::HashMap<String, Vec<Option<Value>>>>,
+    /// Paths whose reads fail with this status.
+    failures: std::collections::HashMap<String, u16>,
+    /// Paths whose reads panic, like a bug in the code serving them.
+    panics: std::collections::HashSet<String>,
+    /// How long each read takes.
+    delay: std::time::Duration,
+    reads: std::sync::atomic::AtomicUsize,
+    /// The operations that reached the store, e.g. `delete_latest secret/app/db`; secrets are
+    /// the same under every mount.
+    calls: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockSecretStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(self, path: &str, secret: Value) -> Self {
+        self.insert(path, secret);
+        self
+    }
+
+    /// Writes a new version of `path` without going through the trait.
+    pub fn insert(&self, path: &str, secret: Value) -> u64 {
+        let mut secrets = self.secrets.lock().unwrap();
+        let versions = secrets.entry(path.to_owned()).or_default();
+        versions.push(Some(secret));
+        versions.len() as u64
+    }
+
+    pub fn failing(mut self, path: &str, code: u16) -> Self {
+        self.failures.insert(path.to_owned(), code);
+        self
+    }
+
+    pub fn panicking(mut self, path: &str) -> Self {
+        self.panics.insert(path.to_owned());
+        self
+    }
+
+    pub fn delayed(mut self, delay: std::time::Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, operation: &str, mount: &str, path: &str) {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("{} {}/{}", operation, mount, path));
+    }
+
+    /// The given version of `path`, or its latest with `None`.
+    fn version(&self, path: &str, version: Option<u64>) -> Result<Value, ClientError> {
+        self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if self.panics.contains(path) {
+            panic!("reading {} panicked", path);
+        }
+        if let Some(code) = self.failures.get(path) {
+            return Err(ClientError::APIError {
+                code: *code,
+                errors: vec![],
+            });
+        }
+        let secrets = self.secrets.lock().unwrap();
+        let versions = secrets.get(path).map(Vec::as_slice).unwrap_or_default();
+        let secret = match version {
+            Some(version) => versions.get((version as usize).wrapping_sub(1)),
+            None => versions.last(),
+        };
+        secret.cloned().flatten().ok_or_else(not_found)
+    }
+}
+
+#[cfg(test)]
+fn not_found() -> ClientError {
+    ClientError::APIError {
+        code: 404,
+        errors: vec![],
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SecretStore for MockSecretStore {
+    async fn read(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Value, ClientError> {
+        self.record("read", mount, path);
+        // Read first, like a response that is slow to arrive
+        let secret = self.version(path, None);
+        tokio::time::sleep(self.delay).await;
+        secret
+    }
+
+    async fn read_version(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        version: u64,
+    ) -> Result<Value, ClientError> {
+        self.record("read_version", mount, path);
+        self.version(path, Some(version))
+    }
+
+    async fn read_wrapped(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        ttl: &str,
+    ) -> Result<WrapInfo, ClientError> {
+        self.record("read_wrapped", mount, path);
+        self.version(path, None)?;
+        Ok(WrapInfo {
+            token: "hvs.wrapped".into(),
+            accessor: "accessor".into(),
+            ttl: ttl.trim_end_matches('s').parse().unwrap_or(300),
+            creation_time: "2024-01-01T00:00:00Z".into(),
+            creation_path: format!("{}/data/{}", mount, path),
+        })
+    }
+
+    async fn write(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+        secret: &Map<String, Value>,
+    ) -> Result<Option<SecretVersionMetadata>, ClientError> {
+        self.record("write", mount, path);
+        let version = self.insert(path, Value::Object(secret.clone()));
+        Ok(Some(SecretVersionMetadata {
+            created_time: "2024-01-01T00:00:00Z".into(),
+            deletion_time: String::new(),
+            destroyed: false,
+            version,
+        }))
+    }
+
+    async fn delete_latest(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        self.record("delete_latest", mount, path);
+        let mut secrets = self.secrets.lock().unwrap();
+        if let Some(latest) = secrets.get_mut(path).and_then(|v| v.last_mut()) {
+            *latest = None;
+        }
+        Ok(())
+    }
+
+    async fn destroy(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<(), ClientError> {
+        self.record("destroy", mount, path);
+        self.secrets.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    async fn list(
+        &self,
+        _namespace: Option<&str>,
+        mount: &str,
+        path: &str,
+    ) -> Result<Vec<String>, ClientError> {
+        self.record("list", mount, path);
+        let prefix = format!("{}/", path);
+        let mut keys: Vec<String> = self
+            .secrets
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .map(|rest| match rest.split_once('/') {
+                Some((dir, _)) => format!("{}/", dir),
+                None => rest.to_owned(),
+            })
+            .collect();
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() {
+            return Err(not_found());
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, fake_vault, send, test_settings, vault_response, AppState, Settings};
+    use axum::{body::Body, http::Request, http::StatusCode, routing::get, Router};
+    use serde_json::json;
+
+    async fn read_through(vault_kv_version: u8, vault: Router) -> (StatusCode, serde_json::Value) {
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            vault_kv_version,
+            ..test_settings()
+        };
+        let router = app(AppState::against_vault(settings));
+        let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        send(router, request).await
+    }
+
+    #[tokio::test]
+    async fn reads_from_a_kv_v1_engine() {
+        let vault = Router::new().route(
+            "/v1/secret/app/db",
+            get(|| async { vault_response(json!({ "password" : "hunter2" })) }),
+        );
+
+        let (status, body) = read_through(1, vault).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn reads_from_a_kv_v2_engine() {
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            get(|| async {
+                vault_response(json!({
+                    "data" : { "password" : "hunter2" },
+                    "metadata" : {
+                        "created_time" : "2024-05-01T09:30:00Z",
+                        "deletion_time" : "",
+                        "destroyed" : false,
+                        "version" : 3,
+                    },
+                }))
+            }),
+        );
+
+        let (status, body) = read_through(2, vault).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "password" : "hunter2" }));
+    }
+
+    #[tokio::test]
+    async fn missing_required_secret_is_named() {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let paths = ["app/db".to_owned(), "app/api".to_owned()];
+
+        let error = require(&store, "secret", &paths).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "required secrets are not readable: app/api (missing)"
+        );
+        assert!(require(&store, "secret", &paths[..1]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_abandoned_early() {
+        use futures::StreamExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 1600;
+        let sent = Arc::new(AtomicUsize::new(0));
+        let vault = Router::new().route(
+            "/v1/secret/data/app/huge",
+            get({
+                let sent = sent.clone();
+                // About 100 MiB, streamed without a Content-Length
+                move || {
+                    let sent = sent.clone();
+                    let chunks = futures::stream::iter(0..CHUNKS).map(move |_| {
+                        sent.fetch_add(1, Ordering::SeqCst);
+                        Ok::<_, std::io::Error>(vec![b' '; CHUNK])
+                    });
+                    async { axum::body::StreamBody::new(chunks) }
+                }
+            }),
+        );
+        let settings = Settings {
+            vault_address: fake_vault(vault).await,
+            vault_max_response_bytes: 1024 * 1024,
+            ..test_settings()
+        };
+        let router = app(AppState::against_vault(settings));
+
+        let request = Request::get("/secret/app/huge")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(router, request).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["error"]["code"], "upstream_error");
+        // Far from all of it was produced, let alone buffered
+        assert!(sent.load(Ordering::SeqCst) < CHUNKS / 4, "{:?}", sent);
+    }
+
+    #[tokio::test]
+    async fn swapping_the_client_moves_reads_to_the_new_vault() {
+        let serving = |password: &'static str| {
+            Router::new().route(
+                "/v1/secret/app/db",
+                get(move || async move { vault_response(json!({ "password" : password })) }),
+            )
+        };
+        let settings = Settings {
+            vault_address: fake_vault(serving("old")).await,
+            vault_kv_version: 1,
+            ..test_settings()
+        };
+        let state = AppState::against_vault(settings);
+        let router = app(state.clone());
+        let read = || Request::get("/secret/app/db").body(Body::empty()).unwrap();
+        assert_eq!(send(router.clone(), read()).await.1["password"], "old");
+
+        let moved = Settings {
+            vault_address: fake_vault(serving("new")).await,
+            ..test_settings()
+        };
+        let lease = vault::Lease {
+            ttl: std::time::Duration::from_secs(3600),
+            renewable: true,
+        };
+        let client = vault::client(&moved).unwrap();
+        state.renewer.replace(&state.vault, client, lease).await;
+
+        assert_eq!(send(router, read()).await.1["password"], "new");
+    }
+}
@@ -0,0 +1,435 @@
+use crate::settings::{LogFormat, Settings, TracingExporter};
+use crate::vault;
+use anyhow::anyhow;
+use opentelemetry::sdk::metrics::MeterProvider;
+use opentelemetry::sdk::trace::Tracer;
+use opentelemetry_otlp::WithExportConfig;
+use std::io::IsTerminal;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::ParseError, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, reload,
+    util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+/// Swaps the active log filter without restarting.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// `RUST_LOG` takes precedence over the configured `log_filter`.
+pub fn env_filter(settings: &Settings) -> Result<EnvFilter, ParseError> {
+    EnvFilter::try_new(std::env::var("RUST_LOG").unwrap_or_else(|_| settings.log_filter.clone()))
+}
+
+/// Installs the configured span exporter and the global tracing subscriber.
+pub fn init_tracing(settings: &Settings) -> anyhow::Result<LogFilterHandle> {
+    opentelemetry::global::set_error_handler(|error| {
+        ::tracing::error!(target: "opentelemetry", "OpenTelemetry error occurred: {:#}", anyhow!(error));
+    })?;
+    set_propagator();
+
+    let (tracer, tracer_error) = tracer_or_fallback(settings)?;
+
+    let (filter, handle) = reload::Layer::new(env_filter(settings)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .with(fmt_layer(
+            settings.log_format(),
+            std::io::stdout().is_terminal(),
+            std::io::stdout,
+        ))
+        .init();
+
+    // Record panics as events of the span that was active, i.e. of the failing request
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let location = info.location().map(ToString::to_string);
+        tracing::error!(
+            panic.location = location.as_deref().unwrap_or("unknown"),
+            "panicked: {}",
+            message
+        );
+    }));
+
+    if let Some(e) = tracer_error {
+        tracing::error!(
+            "failed to install the span exporter, continuing without tracing: {:#}",
+            e
+        );
+    }
+    Ok(handle)
+}
+
+/// The configured tracer, or, when it fails to install and `tracing_required` is false, none
+/// and the error to log once the subscriber is up.
+fn tracer_or_fallback(
+    settings: &Settings,
+) -> anyhow::Result<(Option<Tracer>, Option<anyhow::Error>)> {
+    match tracer(settings) {
+        Ok(tracer) => Ok((tracer, None)),
+        Err(e) if !settings.tracing_required => Ok((None, Some(e))),
+        Err(e) => Err(e),
+    }
+}
+
+fn tracer(settings: &Settings) -> anyhow::Result<Option<Tracer>> {
+    Ok(match &settings.tracing_exporter {
+        TracingExporter::Datadog => Some(
+            DatadogOptions::new(settings)
+                .apply(opentelemetry_datadog::new_pipeline())
+                .install_batch(opentelemetry::runtime::Tokio)?,
+        ),
+        TracingExporter::Otlp { endpoint } => Some(
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .install_batch(opentelemetry::runtime::Tokio)?,
+        ),
+        TracingExporter::None => None,
+    })
+}
+
+/// Writes log lines to `writer` in `format`.
+fn fmt_layer<S, W>(format: LogFormat, ansi: bool, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Json => fmt.json().boxed(),
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+    }
+}
+
+/// Continues traces started by callers that send `traceparent`/`tracestate`, and passes them
+/// on to Vault.
+fn set_propagator() {
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+    );
+}
+
+/// The options of the Datadog pipeline; unset ones keep the exporter's defaults.
+#[derive(Debug, Default, PartialEq)]
+struct DatadogOptions {
+    agent_endpoint: Option<String>,
+    service_name: Option<String>,
+    env: Option<String>,
+}
+
+impl DatadogOptions {
+    fn new(settings: &Settings) -> Self {
+        DatadogOptions {
+            agent_endpoint: settings.dd_agent_endpoint.clone(),
+            service_name: settings.dd_service_name.clone(),
+            env: settings.dd_env.clone(),
+        }
+    }
+
+    fn apply(
+        &self,
+        mut pipeline: opentelemetry_datadog::DatadogPipelineBuilder,
+    ) -> opentelemetry_datadog::DatadogPipelineBuilder {
+        if let Some(endpoint) = &self.agent_endpoint {
+            pipeline = pipeline.with_agent_endpoint(endpoint);
+        }
+        if let Some(service_name) = &self.service_name {
+            pipeline = pipeline.with_service_name(service_name);
+        }
+        if let Some(env) = &self.env {
+            pipeline = pipeline.with_env(env);
+        }
+        pipeline
+    }
+}
+
+/// Exports OpenTelemetry metrics alongside OTLP traces; the Datadog exporter only handles traces.
+/// Vault call counts and durations are on `/metrics` as well, whatever the exporter.
+///
+/// Shares the OTLP collector with traces, so `tracing_required` applies to it too.
+pub fn init_metrics(settings: &Settings) -> anyhow::Result<Option<MeterProvider>> {
+    let TracingExporter::Otlp { endpoint } = &settings.tracing_exporter else {
+        return Ok(None);
+    };
+    let provider = opentelemetry_otlp::new_pipeline()
+        .metrics(opentelemetry::runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .build();
+    match provider {
+        Ok(provider) => {
+            vault::record_into(&provider);
+            Ok(Some(provider))
+        }
+        Err(e) if !settings.tracing_required => {
+            tracing::error!(
+                "failed to install the metrics exporter, continuing without it: {:#}",
+                anyhow!(e)
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MockSecretStore;
+    use futures::future::BoxFuture;
+    use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::sync::{Arc, Mutex};
+
+    /// Collects the spans that end.
+    #[derive(Debug, Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Spans {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn request_span_continues_an_inbound_trace() {
+        use crate::{app, send, test_settings, AppState};
+        use axum::{body::Body, http::Request};
+        use opentelemetry::trace::{SpanKind, TracerProvider as _};
+
+        set_propagator();
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+        let spans = Spans::default();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("spot-tests")));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let request = Request::get("/")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        send(router, request).await;
+        provider.force_flush();
+
+        let spans = spans.0.lock().unwrap();
+        let request_span = spans
+            .iter()
+            .find(|span| span.span_kind == SpanKind::Server)
+            .unwrap_or_else(|| panic!("no server span in {:?}", spans));
+        assert_eq!(
+            request_span.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[tokio::test]
+    async fn probes_are_traced() {
+        use crate::{app, send, test_settings, AppState};
+        use axum::{body::Body, http::Request};
+        use opentelemetry::trace::{SpanKind, TracerProvider as _};
+
+        set_propagator();
+        let router = app(AppState::for_tests(
+            test_settings(),
+            Arc::new(MockSecretStore::new()),
+        ));
+        let spans = Spans::default();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("spot-tests")));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let uris = ["/health", "/ready", "/version", "/openapi.json"];
+
+        for uri in uris {
+            let request = Request::get(uri)
+                .header(
+                    "traceparent",
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                )
+                .body(Body::empty())
+                .unwrap();
+            send(router.clone(), request).await;
+        }
+        provider.force_flush();
+
+        let spans = spans.0.lock().unwrap();
+        let traced = spans
+            .iter()
+            .filter(|span| span.span_kind == SpanKind::Server)
+            .filter(|span| {
+                span.span_context.trace_id().to_string() == "4bf92f3577b34da6a3ce929d0e0e4736"
+            })
+            .count();
+        assert_eq!(traced, uris.len(), "{:?}", spans);
+    }
+
+    #[tokio::test]
+    async fn vault_reads_carry_the_inbound_trace() {
+        use crate::{app, fake_vault, send, test_settings, vault_response, AppState};
+        use axum::{body::Body, http::HeaderMap, http::Request, routing::get, Router};
+        use opentelemetry::trace::TracerProvider as _;
+        use serde_json::json;
+
+        set_propagator();
+        let received = Arc::new(Mutex::new(None));
+        let vault = Router::new().route(
+            "/v1/secret/data/app/db",
+            get({
+                let received = received.clone();
+                move |headers: HeaderMap| async move {
+                    *received.lock().unwrap() = headers.get("traceparent").cloned();
+                    vault_response(json!({
+                        "data" : { "password" : "hunter2" },
+                        "metadata" : {
+                            "created_time" : "2024-05-01T09:30:00Z",
+                            "deletion_time" : "",
+                            "destroyed" : false,
+                            "version" : 1,
+                        },
+                    }))
+                }
+            }),
+        );
+        let settings = crate::Settings {
+            vault_address: fake_vault(vault).await,
+            ..test_settings()
+        };
+        let router = app(AppState::against_vault(settings));
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(Spans::default())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("spot-tests")));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let request = Request::get("/secret/app/db")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        let (status, _) = send(router, request).await;
+
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let traceparent = received.lock().unwrap().take().expect("a traceparent");
+        assert!(
+            traceparent
+                .to_str()
+                .unwrap()
+                .starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"),
+            "{:?}",
+            traceparent
+        );
+    }
+
+    #[test]
+    fn datadog_options_are_read_from_the_settings() {
+        let settings: Settings = config::Config::builder()
+            .set_override("dd_agent_endpoint", "http://datadog-agent:8126")
+            .unwrap()
+            .set_override("dd_service_name", "spot-billing")
+            .unwrap()
+            .set_override("dd_env", "staging")
+            .unwrap()
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(
+            DatadogOptions::new(&settings),
+            DatadogOptions {
+                agent_endpoint: Some("http://datadog-agent:8126".into()),
+                service_name: Some("spot-billing".into()),
+                env: Some("staging".into()),
+            }
+        );
+        assert_eq!(
+            DatadogOptions::new(&Settings::default()),
+            DatadogOptions::default()
+        );
+    }
+
+    /// What the layer for `format` writes for one event in a span.
+    fn logged(format: LogFormat) -> String {
+        let buffer = crate::Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(format, false, {
+            let buffer = buffer.clone();
+            move || buffer.clone()
+        }));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("request", path = "app/db")
+                .in_scope(|| tracing::info!(status = 200, "served"))
+        });
+        buffer.contents()
+    }
+
+    #[test]
+    fn each_log_format_selects_its_layer() {
+        let json: serde_json::Value = serde_json::from_str(&logged(LogFormat::Json)).unwrap();
+        assert_eq!(json["fields"]["message"], "served");
+        assert_eq!(json["span"]["path"], "app/db");
+
+        let pretty = logged(LogFormat::Pretty);
+        assert!(pretty.lines().count() > 1, "{}", pretty);
+        assert!(
+            pretty.contains("in spot::telemetry::tests::request"),
+            "{}",
+            pretty
+        );
+
+        let compact = logged(LogFormat::Compact);
+        assert_eq!(compact.lines().count(), 1, "{}", compact);
+        assert!(compact.contains("request:"), "{}", compact);
+        assert!(serde_json::from_str::<serde_json::Value>(&compact).is_err());
+    }
+
+    fn unreachable_collector(tracing_required: bool) -> Settings {
+        Settings {
+            tracing_exporter: TracingExporter::Otlp {
+                endpoint: "not a collector uri".into(),
+            },
+            tracing_required,
+            ..Settings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn continues_without_a_tracer_that_fails_to_install() {
+        let (tracer, error) = tracer_or_fallback(&unreachable_collector(false)).unwrap();
+        assert!(tracer.is_none());
+        assert!(error.is_some());
+    }
+
+    #[tokio::test]
+    async fn required_tracer_that_fails_to_install_is_an_error() {
+        assert!(tracer_or_fallback(&unreachable_collector(true)).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Breaks the time spent on a request down into phases, reported in an `X-Timing` header
+//! when `timing_header_enabled` is set.
+
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// Time per phase, in the order the phases first ran, for the request being handled.
+    static PHASES: RefCell<Vec<(&'static str, Duration)>>;
+}
+
+/// Adds `elapsed` to `phase`; a no-op outside [`report`], so cheap when timing is disabled.
+pub fn record(phase: &'static str, elapsed: Duration) {
+    let _ = PHASES.try_with(|phases| {
+        tracing::debug!(phase, elapsed_ms = elapsed.as_secs_f64() * 1000.0, "timing");
+        let mut phases = phases.borrow_mut();
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+    });
+}
+
+/// A response whose conversion, e.g. serializing a JSON body, is recorded as `serialize`.
+pub struct Timed<T>(pub T);
+
+impl<T: IntoResponse> IntoResponse for Timed<T> {
+    fn into_response(self) -> Response {
+        let started = Instant::now();
+        let response = self.0.into_response();
+        record("serialize", started.elapsed());
+        response
+    }
+}
+
+/// Collects the phases of a request and reports them, with the total, as
+/// `X-Timing: auth_check;dur=0.05, vault_call;dur=3.20, serialize;dur=0.01, total;dur=3.40`,
+/// durations in milliseconds.
+///
+/// Phases are summed, so concurrent Vault calls, as in a batch, can add up to more than the
+/// total.
+pub async fn report<B>(req: Request<B>, next: Next<B>) -> Response {
+    let started = Instant::now();
+    let (mut response, phases) = PHASES
+        .scope(RefCell::new(Vec::new()), async {
+            let response = next.run(req).await;
+            (response, PHASES.with(RefCell::take))
+        })
+        .await;
+
+    let mut header = String::new();
+    for (phase, elapsed) in phases.into_iter().chain([("total", started.elapsed())]) {
+        let separator = if header.is_empty() { "" } else { ", " };
+        let _ = write!(
+            header,
+            "{}{};dur={:.2}",
+            separator,
+            phase,
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+    if let Ok(value) = HeaderValue::from_str(&header) {
+        response.headers_mut().insert("x-timing", value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MockSecretStore;
+    use crate::{app, test_settings, AppState, Settings};
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn timing_header(timing_header_enabled: bool) -> Option<String> {
+        let store = MockSecretStore::new().with("app/db", json!({ "password" : "hunter2" }));
+        let settings = Settings {
+            timing_header_enabled,
+            ..test_settings()
+        };
+        let router = app(AppState::for_tests(settings, Arc::new(store)));
+        let request = Request::get("/secret/app/db").body(Body::empty()).unwrap();
+
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers().get("x-timing")?;
+        Some(header.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn phases_are_reported_when_enabled() {
+        let header = timing_header(true).await.expect("an X-Timing header");
+
+        let phases: Vec<(&str, f64)> = header
+            .split(", ")
+            .map(|phase| {
+                let (name, duration) = phase.split_once(";dur=").expect("name;dur=ms");
+                (name, duration.parse().expect("milliseconds"))
+            })
+            .collect();
+        assert!(
+            phases.iter().any(|(name, _)| *name == "serialize"),
+            "{}",
+            header
+        );
+        let (last, total) = phases.last().unwrap();
+        assert_eq!(*last, "total");
+        assert!(*total >= 0.0);
+    }
+
+    #[tokio::test]
+    async fn no_header_when_disabled() {
+        assert_eq!(timing_header(false).await, None);
+    }
+}
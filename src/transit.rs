@@ -0,0 +1,168 @@
+use crate::settings::Settings;
+use crate::vault;
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use base64::Engine;
+use serde_json::Value;
+use vaultrs::client::{Client, VaultClient};
+use vaultrs::error::ClientError;
+
+/// Marks a config string as transit ciphertext, e.g. `enc:vault:v1:...`.
+const PREFIX: &str = "enc:";
+
+/// Decrypts transit ciphertext, given without the [`PREFIX`], to base64 plaintext.
+#[async_trait]
+trait Decryptor: Sync {
+    async fn decrypt(&self, ciphertext: &str) -> Result<String, ClientError>;
+}
+
+/// The key configured as `transit`, used with the bootstrap token.
+struct TransitKey<'a> {
+    client: VaultClient,
+    mount: &'a str,
+    key: &'a str,
+}
+
+#[async_trait]
+impl Decryptor for TransitKey<'_> {
+    async fn decrypt(&self, ciphertext: &str) -> Result<String, ClientError> {
+        vault::call(
+            "transit.decrypt",
+            vaultrs::transit::data::decrypt(&self.client, self.mount, self.key, ciphertext, None),
+        )
+        .await
+        .map(|response| response.plaintext)
+    }
+}
+
+/// Replaces every `enc:` string in `settings` with its plaintext, decrypted with the
+/// configured transit key.
+pub async fn decrypt(settings: Settings) -> anyhow::Result<Settings> {
+    let mut value = serde_json::to_value(&settings)?;
+    let mut encrypted = Vec::new();
+    collect(&mut value, String::new(), &mut encrypted);
+    let Some((field, _)) = encrypted.first() else {
+        return Ok(settings);
+    };
+
+    let Some(transit) = &settings.transit else {
+        bail!("{} is encrypted but no transit key is configured", field);
+    };
+    let token = transit
+        .token
+        .as_ref()
+        .or(settings.vault_token.as_ref())
+        .filter(|token| !token.starts_with(PREFIX))
+        .ok_or_else(|| anyhow!("decrypting config values needs an unencrypted transit.token"))?;
+    let mut client = vault::client(&settings)?;
+    client.set_token(token);
+    let key = TransitKey {
+        client,
+        mount: &transit.mount,
+        key: &transit.key,
+    };
+    decrypt_with(&settings, &key).await
+}
+
+async fn decrypt_with(settings: &Settings, decryptor: &dyn Decryptor) -> anyhow::Result<Settings> {
+    let mut value = serde_json::to_value(settings)?;
+    let mut encrypted = Vec::new();
+    collect(&mut value, String::new(), &mut encrypted);
+    for (field, value) in encrypted {
+        let Value::String(ciphertext) = value else {
+            unreachable!("only strings are collected")
+        };
+        let plaintext = decryptor
+            .decrypt(&ciphertext[PREFIX.len()..])
+            .await
+            .map_err(|e| anyhow!("failed to decrypt {}: {}", field, vault::reason(e)))?;
+        let plaintext = base64::engine::general_purpose::STANDARD
+            .decode(&plaintext)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .with_context(|| format!("decrypted {} is not UTF-8 text", field))?;
+        *value = Value::String(plaintext);
+    }
+
+    let mut decrypted: Settings = serde_json::from_value(value)?;
+    decrypted.sources = settings.sources.clone();
+    Ok(decrypted)
+}
+
+/// Finds the encrypted strings below `value`, named by their dotted field path.
+fn collect<'a>(value: &'a mut Value, field: String, found: &mut Vec<(String, &'a mut Value)>) {
+    match value {
+        Value::String(s) if s.starts_with(PREFIX) => found.push((field, value)),
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let field = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", field, key)
+                };
+                collect(value, field, found);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                collect(value, format!("{}[{}]", field, i), found);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Knows the plaintext of each ciphertext it was given.
+    struct FakeTransit(HashMap<&'static str, &'static str>);
+
+    #[async_trait]
+    impl Decryptor for FakeTransit {
+        async fn decrypt(&self, ciphertext: &str) -> Result<String, ClientError> {
+            match self.0.get(ciphertext) {
+                Some(plaintext) => Ok(base64::engine::general_purpose::STANDARD.encode(plaintext)),
+                None => Err(ClientError::APIError {
+                    code: 400,
+                    errors: vec!["invalid ciphertext".into()],
+                }),
+            }
+        }
+    }
+
+    fn transit() -> FakeTransit {
+        FakeTransit(HashMap::from([(
+            "vault:v1:c3BvdA==",
+            "spot-api-token-value",
+        )]))
+    }
+
+    #[tokio::test]
+    async fn encrypted_values_are_replaced_with_their_plaintext() {
+        let settings = Settings {
+            api_token: Some("enc:vault:v1:c3BvdA==".into()),
+            vault_mount: "secret".into(),
+            ..Settings::default()
+        };
+
+        let decrypted = decrypt_with(&settings, &transit()).await.unwrap();
+
+        assert_eq!(decrypted.api_token.as_deref(), Some("spot-api-token-value"));
+        assert_eq!(decrypted.vault_mount, "secret");
+    }
+
+    #[tokio::test]
+    async fn undecryptable_value_is_an_error_naming_the_field() {
+        let settings = Settings {
+            api_token: Some("enc:vault:v1:b3RoZXI=".into()),
+            ..Settings::default()
+        };
+
+        let error = decrypt_with(&settings, &transit()).await.unwrap_err();
+
+        assert!(error.to_string().contains("api_token"), "{}", error);
+    }
+}
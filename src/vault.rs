@@ -0,0 +1,1205 @@
+use crate::breaker;
+use crate::settings::{Settings, VaultAuth};
+use crate::shutdown;
+use crate::timing;
+use anyhow::{anyhow, bail, Context};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use opentelemetry::metrics::{Counter, Histogram, Meter, MeterProvider, Unit};
+use opentelemetry::KeyValue;
+use opentelemetry_http::HeaderInjector;
+use rustify::clients::reqwest::Client as HttpClient;
+use rustify::endpoint::Endpoint;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::{watch, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use vaultrs::api::{AuthInfo, EndpointError, EndpointMiddleware, EndpointResult, WrapInfo};
+use vaultrs::client::{Client, VaultClient, VaultClientSettings, VaultClientSettingsBuilder};
+use vaultrs::error::ClientError;
+
+struct VaultMetrics {
+    duration: Histogram<f64>,
+    calls: Counter<u64>,
+}
+
+impl VaultMetrics {
+    fn new(meter: &Meter) -> Self {
+        VaultMetrics {
+            duration: meter
+                .f64_histogram("vault.operation.duration")
+                .with_unit(Unit::new("s"))
+                .with_description("Duration of Vault requests")
+                .init(),
+            calls: meter
+                .u64_counter("vault.operations")
+                .with_description("Vault requests by operation and outcome")
+                .init(),
+        }
+    }
+}
+
+/// Set by [`record_into`] once there is a meter provider; until then Vault calls are not
+/// recorded, rather than bound for good to the no-op global meter.
+static METRICS: OnceLock<VaultMetrics> = OnceLock::new();
+
+/// Records every later Vault call into `provider`.
+pub fn record_into(provider: &impl MeterProvider) {
+    let _ = METRICS.set(VaultMetrics::new(&provider.meter("spot")));
+}
+
+tokio::task_local! {
+    /// When the request being served times out; Vault calls made for it stop there.
+    pub static DEADLINE: tokio::time::Instant;
+}
+
+/// Vault calls are not started with less time than this left before [`DEADLINE`].
+const MIN_BUDGET: Duration = Duration::from_millis(50);
+
+/// Time left before [`DEADLINE`], outside of requests `None`.
+fn budget() -> Option<Duration> {
+    DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(tokio::time::Instant::now()))
+        .ok()
+}
+
+/// Reported like a gateway timeout from Vault, which [`crate::error::AppError`] turns into 504.
+fn deadline_exceeded(message: &str) -> ClientError {
+    ClientError::APIError {
+        code: 504,
+        errors: vec![message.to_owned()],
+    }
+}
+
+/// Given for calls refused while the [`breaker`] is open.
+pub const CIRCUIT_OPEN: &str = "Vault circuit breaker is open, not calling Vault";
+
+/// Reported like an unavailable Vault, which [`crate::error::AppError`] turns into 503.
+fn circuit_open() -> ClientError {
+    ClientError::APIError {
+        code: 503,
+        errors: vec![CIRCUIT_OPEN.to_owned()],
+    }
+}
+
+/// Whether `error` is a call refused by the open [`breaker`] rather than one made.
+pub fn is_circuit_open(error: &ClientError) -> bool {
+    matches!(error, ClientError::APIError { code: 503, errors } if errors.first().map(String::as_str) == Some(CIRCUIT_OPEN))
+}
+
+/// Every Vault request goes through here so that all of them are measured, and bounded by
+/// the [`DEADLINE`] of the request they are made for.
+pub async fn call<T>(
+    operation: &'static str,
+    request: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    measured(METRICS.get(), operation, request).await
+}
+
+/// [`call`], recording into `metrics` if given.
+async fn measured<T>(
+    metrics: Option<&VaultMetrics>,
+    operation: &'static str,
+    request: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    let budget = budget();
+    if budget.is_some_and(|budget| budget < MIN_BUDGET) {
+        return Err(deadline_exceeded(
+            "too little of the request timeout left to call Vault",
+        ));
+    }
+
+    let admission = match breaker::get() {
+        Some(breaker) => match breaker.admit() {
+            Some(admission) => Some(admission),
+            None => {
+                metrics::increment_counter!("vault_circuit_breaker_rejections_total");
+                return Err(circuit_open());
+            }
+        },
+        None => None,
+    };
+
+    let started = Instant::now();
+    let result = match budget {
+        Some(budget) => tokio::time::timeout(budget, request)
+            .await
+            .unwrap_or_else(|_| Err(deadline_exceeded("request timed out waiting for Vault"))),
+        None => request.await,
+    };
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    let attributes = [
+        KeyValue::new("operation", operation),
+        KeyValue::new("outcome", outcome),
+    ];
+    if let Some(admission) = admission {
+        admission.finish(!result.as_ref().is_err_and(is_retryable));
+    }
+    let elapsed = started.elapsed();
+    timing::record("vault_call", elapsed);
+    if let Some(metrics) = metrics {
+        metrics.duration.record(elapsed.as_secs_f64(), &attributes);
+        metrics.calls.add(1, &attributes);
+    }
+    // Also on /metrics, as the meter is only exported alongside OTLP traces
+    metrics::increment_counter!(
+        "vault_operations_total",
+        "operation" => operation,
+        "outcome" => outcome
+    );
+    metrics::histogram!(
+        "vault_operation_duration_seconds",
+        elapsed.as_secs_f64(),
+        "operation" => operation,
+        "outcome" => outcome
+    );
+    result
+}
+
+/// How a read or list ended, as the `outcome` label of the counters kept for them.
+pub fn outcome<T>(result: &Result<T, ClientError>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(ClientError::APIError { code: 404, .. }) => "not_found",
+        Err(_) => "failure",
+    }
+}
+
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Whether a failed request may succeed if sent again, e.g. after a leader election.
+pub fn is_retryable(error: &ClientError) -> bool {
+    if is_circuit_open(error) {
+        return false;
+    }
+    match error {
+        ClientError::APIError { code, .. } => *code >= 500 && *code != 501,
+        ClientError::RestClientError {
+            source: rustify::errors::ClientError::RequestError { .. },
+        } => true,
+        _ => false,
+    }
+}
+
+/// Repeats [`call`] up to `attempts` times, with jittered exponential backoff, while the
+/// error [`is_retryable`].
+pub async fn call_with_retry<T, F, Fut>(
+    operation: &'static str,
+    attempts: u32,
+    mut request: F,
+) -> Result<T, ClientError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ClientError>>,
+{
+    let mut backoff = MIN_RETRY_DELAY;
+    for attempt in 1.. {
+        match call(operation, request()).await {
+            Err(e) if attempt < attempts && is_retryable(&e) => {
+                // Full jitter keeps replicas from retrying in lockstep
+                let delay = backoff.mul_f64(rand::random());
+                if budget().is_some_and(|budget| budget < delay + MIN_BUDGET) {
+                    return Err(e);
+                }
+                tracing::warn!(
+                    "{} failed (attempt {}/{}), retrying in {}ms: {}",
+                    operation,
+                    attempt,
+                    attempts,
+                    delay.as_millis(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+    unreachable!("the retry loop only exits by returning")
+}
+
+const MIN_RENEW_DELAY: Duration = Duration::from_secs(1);
+const MAX_RENEW_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The time-to-live of the token the client is currently using.
+#[derive(Debug, Clone, Copy)]
+pub struct Lease {
+    pub ttl: Duration,
+    pub renewable: bool,
+}
+
+impl From<&AuthInfo> for Lease {
+    fn from(info: &AuthInfo) -> Self {
+        Lease {
+            ttl: Duration::from_secs(info.lease_duration),
+            renewable: info.renewable,
+        }
+    }
+}
+
+pub async fn connect(settings: &Settings) -> anyhow::Result<(VaultClient, Lease)> {
+    let auth = settings.vault_auth.as_ref().ok_or_else(|| {
+        anyhow!("no Vault token provided, set SPOT_VAULT_TOKEN or configure vault_auth")
+    })?;
+
+    let mut client = client(settings)?;
+
+    let lease = match auth {
+        VaultAuth::Token { token } => {
+            client.set_token(token);
+            let token = call("token.lookup_self", vaultrs::token::lookup_self(&client))
+                .await
+                .map_err(|e| anyhow!("Vault token lookup failed: {}", reason(e)))?;
+            Lease {
+                ttl: Duration::from_secs(token.ttl),
+                renewable: token.renewable,
+            }
+        }
+        _ => {
+            let info = login(&client, auth).await?;
+            client.set_token(&info.client_token);
+            Lease::from(&info)
+        }
+    };
+
+    Ok((client, lease))
+}
+
+/// Retries [`connect`] with exponential backoff, up to `vault_connect_attempts` times.
+pub async fn connect_with_retry(settings: &Settings) -> anyhow::Result<(VaultClient, Lease)> {
+    retry_connect(settings.vault_connect_attempts, || connect(settings)).await
+}
+
+async fn retry_connect<T, F, Fut>(attempts: u32, mut connect: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let attempts = attempts.max(1);
+    let mut backoff = MIN_RENEW_DELAY;
+    for attempt in 1.. {
+        match connect().await {
+            Ok(connected) => return Ok(connected),
+            Err(e) if attempt < attempts => {
+                tracing::warn!(
+                    "failed to connect to Vault (attempt {}/{}), retrying in {}s: {:#}",
+                    attempt,
+                    attempts,
+                    backoff.as_secs(),
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RENEW_BACKOFF);
+            }
+            Err(e) => {
+                return Err(e.context(format!("giving up on Vault after {} attempts", attempts)))
+            }
+        }
+    }
+    unreachable!("the retry loop only exits by returning")
+}
+
+/// Keeps trying to connect after a fail-open start, then renews the token as usual.
+pub async fn reconnect(
+    vault: Arc<ArcSwap<VaultClient>>,
+    settings: Arc<ArcSwap<Settings>>,
+    renewer: Arc<Renewer>,
+    connected: Arc<AtomicBool>,
+    shutdown: CancellationToken,
+) {
+    let mut backoff = MIN_RENEW_DELAY;
+    loop {
+        if !shutdown::sleep(&shutdown, backoff).await {
+            return;
+        }
+        // A configuration reload with a new vault_address may have connected meanwhile
+        if connected.load(Ordering::Acquire) {
+            break;
+        }
+        match connect(&settings.load()).await {
+            Ok((client, lease)) => {
+                renewer.replace(&vault, client, lease).await;
+                break;
+            }
+            Err(e) => {
+                backoff = (backoff * 2).min(MAX_RENEW_BACKOFF);
+                tracing::warn!(
+                    "still unable to connect to Vault, retrying in {}s: {:#}",
+                    backoff.as_secs(),
+                    e
+                );
+            }
+        }
+    }
+    connected.store(true, Ordering::Release);
+    tracing::info!("connected to Vault");
+    renew_token(vault, settings.load_full(), renewer, shutdown).await
+}
+
+/// Shares a client's connection and token but sends requests to another namespace.
+pub struct Namespaced {
+    client: Arc<VaultClient>,
+    middle: EndpointMiddleware,
+}
+
+impl Namespaced {
+    /// Keeps the client's own namespace when `namespace` is `None`.
+    pub fn new(client: Arc<VaultClient>, namespace: Option<&str>) -> Self {
+        let mut middle = client.middle.clone();
+        if let Some(namespace) = namespace {
+            middle.namespace = Some(namespace.to_owned());
+        }
+        Namespaced { client, middle }
+    }
+}
+
+#[async_trait]
+impl Client for Namespaced {
+    fn http(&self) -> &HttpClient {
+        self.client.http()
+    }
+
+    fn middle(&self) -> &EndpointMiddleware {
+        &self.middle
+    }
+
+    fn settings(&self) -> &VaultClientSettings {
+        self.client.settings()
+    }
+
+    fn set_token(&mut self, token: &str) {
+        self.middle.token = token.to_owned();
+    }
+}
+
+/// A client without a token, using a connection pool tuned by the `vault_pool_*` settings.
+pub fn client(settings: &Settings) -> anyhow::Result<VaultClient> {
+    let context = || {
+        format!(
+            "failed to create Vault client for {}",
+            settings.vault_address
+        )
+    };
+    let mut client = VaultClient::new(
+        VaultClientSettingsBuilder::default()
+            .address(&settings.vault_address)
+            .token("")
+            .namespace(settings.vault_namespace.clone())
+            .build()?,
+    )
+    .with_context(context)?;
+
+    // vaultrs has no pool options, so swap in an HTTP client with them set, keeping the
+    // TLS options it read from VAULT_CACERT, VAULT_CAPATH and VAULT_SKIP_VERIFY
+    let mut http = reqwest::Client::builder().danger_accept_invalid_certs(!client.settings.verify);
+    for path in &client.settings.ca_certs {
+        let pem = std::fs::read(path).with_context(|| format!("failed to read CA {}", path))?;
+        http = http.add_root_certificate(
+            reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("invalid CA certificate {}", path))?,
+        );
+    }
+    client.http = HttpClient::new(
+        client.settings.address.as_str(),
+        PoolOptions::new(settings)
+            .apply(http)
+            .build()
+            .with_context(context)?,
+    );
+    Ok(client)
+}
+
+/// The connection pool options of the HTTP client behind [`client`]; unset ones keep
+/// reqwest's defaults.
+#[derive(Debug, Default, PartialEq)]
+struct PoolOptions {
+    max_idle_per_host: Option<usize>,
+    idle_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl PoolOptions {
+    fn new(settings: &Settings) -> Self {
+        PoolOptions {
+            max_idle_per_host: settings.vault_pool_max_idle,
+            idle_timeout: settings
+                .vault_pool_idle_timeout_secs
+                .map(Duration::from_secs),
+            connect_timeout: settings.vault_connect_timeout_secs.map(Duration::from_secs),
+        }
+    }
+
+    fn apply(&self, mut http: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if let Some(max_idle) = self.max_idle_per_host {
+            http = http.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            http = http.pool_idle_timeout(idle_timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            http = http.connect_timeout(connect_timeout);
+        }
+        http
+    }
+}
+
+async fn login(client: &VaultClient, auth: &VaultAuth) -> anyhow::Result<AuthInfo> {
+    match auth {
+        VaultAuth::Token { .. } => bail!("token auth has no login step"),
+        VaultAuth::AppRole {
+            role_id,
+            secret_id,
+            mount,
+        } => {
+            let info = call(
+                "auth.approle.login",
+                vaultrs::auth::approle::login(client, mount, role_id, secret_id),
+            )
+            .await
+            .map_err(|e| anyhow!("AppRole login at {} failed: {}", mount, reason(e)))?;
+            tracing::info!(
+                "logged in to Vault via AppRole, lease duration {}s",
+                info.lease_duration
+            );
+            Ok(info)
+        }
+        VaultAuth::Kubernetes {
+            role,
+            mount,
+            token_path,
+        } => {
+            let jwt = tokio::fs::read_to_string(token_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to read service-account token from {}",
+                        token_path.display()
+                    )
+                })?;
+            let info = call(
+                "auth.kubernetes.login",
+                vaultrs::auth::kubernetes::login(client, mount, role, jwt.trim()),
+            )
+            .await
+            .map_err(|e| anyhow!("Kubernetes login at {} failed: {}", mount, reason(e)))?;
+            tracing::info!(
+                "logged in to Vault via Kubernetes as role {}, lease duration {}s",
+                role,
+                info.lease_duration
+            );
+            Ok(info)
+        }
+    }
+}
+
+/// Renews the client token, logging in again when the auth method allows it.
+async fn renew(vault: &ArcSwap<VaultClient>, auth: &VaultAuth) -> anyhow::Result<Lease> {
+    let client = vault.load_full();
+    let renewed = call(
+        "token.renew_self",
+        vaultrs::token::renew_self(&*client, None),
+    )
+    .await;
+    let info = match (renewed, auth) {
+        (Ok(info), _) => info,
+        (Err(e), VaultAuth::Token { .. }) => bail!("token renewal failed: {}", reason(e)),
+        (Err(e), _) => {
+            tracing::debug!("token renewal failed, logging in again: {}", reason(e));
+            login(&client, auth).await?
+        }
+    };
+    vault.store(Arc::new(with_token(&client, &info.client_token)));
+    Ok(Lease::from(&info))
+}
+
+/// A copy of `client`, sharing its connection pool, that sends `token` instead.
+fn with_token(client: &VaultClient, token: &str) -> VaultClient {
+    let mut client = VaultClient {
+        http: HttpClient::new(&client.http.base, client.http.http.clone()),
+        middle: client.middle.clone(),
+        settings: client.settings.clone(),
+    };
+    client.set_token(token);
+    client
+}
+
+/// Serialises renewals between the background task and on-demand requests.
+pub struct Renewer {
+    lock: Mutex<()>,
+    lease: watch::Sender<Option<Lease>>,
+    renewed_at: std::sync::Mutex<Option<OffsetDateTime>>,
+}
+
+impl Renewer {
+    pub fn new(lease: Option<Lease>) -> Self {
+        Renewer {
+            lock: Mutex::new(()),
+            lease: watch::Sender::new(lease),
+            renewed_at: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// The lease of the current token, `None` until connected.
+    pub fn lease(&self) -> Option<Lease> {
+        *self.lease.borrow()
+    }
+
+    /// Renews now, rescheduling the background renewal from the new lease.
+    pub async fn renew(
+        &self,
+        vault: &ArcSwap<VaultClient>,
+        auth: &VaultAuth,
+    ) -> anyhow::Result<Lease> {
+        let _renewing = self.lock.lock().await;
+        let lease = renew(vault, auth).await?;
+        self.lease.send_replace(Some(lease));
+        *self.renewed_at.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(OffsetDateTime::now_utc());
+        Ok(lease)
+    }
+
+    /// Swaps in `client`, connected with a new token, once no renewal is in progress;
+    /// requests already holding the previous client finish with it.
+    pub async fn replace(&self, vault: &ArcSwap<VaultClient>, client: VaultClient, lease: Lease) {
+        let _renewing = self.lock.lock().await;
+        vault.store(Arc::new(client));
+        self.lease.send_replace(Some(lease));
+        *self.renewed_at.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(OffsetDateTime::now_utc());
+    }
+
+    /// When the token was last renewed or replaced by a new login, `None` if it never was.
+    pub fn renewed_at(&self) -> Option<OffsetDateTime> {
+        *self.renewed_at.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps the client token alive, renewing at roughly half its TTL.
+pub async fn renew_token(
+    vault: Arc<ArcSwap<VaultClient>>,
+    settings: Arc<Settings>,
+    renewer: Arc<Renewer>,
+    shutdown: CancellationToken,
+) {
+    let Some(auth) = settings.vault_auth.as_ref() else {
+        return;
+    };
+    let interval = settings.vault_renew_interval_secs.map(Duration::from_secs);
+    let mut leases = renewer.lease.subscribe();
+
+    let mut backoff = MIN_RENEW_DELAY;
+    loop {
+        let Some(lease) = *leases.borrow_and_update() else {
+            return;
+        };
+        if lease.ttl.is_zero() && interval.is_none() {
+            tracing::debug!("Vault token does not expire, not renewing");
+            return;
+        }
+        if !lease.renewable && matches!(auth, VaultAuth::Token { .. }) {
+            tracing::warn!(
+                "Vault token is not renewable and expires in {}s",
+                lease.ttl.as_secs()
+            );
+            return;
+        }
+
+        let delay = interval.unwrap_or(lease.ttl / 2).max(MIN_RENEW_DELAY);
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            // Renewed on demand, so start over from the new lease
+            _ = leases.changed() => continue,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        loop {
+            tracing::debug!("renewing Vault token");
+            match renewer.renew(&vault, auth).await {
+                Ok(renewed) => {
+                    tracing::debug!(
+                        "renewed Vault token, lease duration {}s",
+                        renewed.ttl.as_secs()
+                    );
+                    backoff = MIN_RENEW_DELAY;
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "failed to renew Vault token, retrying in {}s: {:#}",
+                        backoff.as_secs(),
+                        e
+                    );
+                    if !shutdown::sleep(&shutdown, backoff).await {
+                        return;
+                    }
+                    backoff = (backoff * 2).min(MAX_RENEW_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+pub async fn health(client: &VaultClient) -> anyhow::Result<()> {
+    call("sys.health", vaultrs::sys::health(client))
+        .await
+        .context("Vault health check failed")?;
+    Ok(())
+}
+
+/// Executes `endpoint` with Vault wrapping its response in a token valid for `ttl`.
+///
+/// Unlike `vaultrs::api::wrap`, which always asks for 10 minutes, the TTL is the caller's.
+pub async fn wrap<E: Endpoint>(
+    client: &impl Client,
+    endpoint: E,
+    ttl: &str,
+) -> Result<WrapInfo, ClientError> {
+    let mut middle = client.middle().clone();
+    middle.wrap = Some(ttl.to_owned());
+    let response = endpoint
+        .with_middleware(&middle)
+        .exec(client.http())
+        .await
+        .map_err(api_error)?;
+    response
+        .wrap::<EndpointResult<E::Response>>()?
+        .wrap_info
+        .ok_or(ClientError::ResponseWrapError)
+}
+
+/// Why a read stopped after [`exec_limited`] had buffered `limit` bytes.
+#[derive(Debug)]
+pub struct ResponseTooLarge {
+    limit: usize,
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+impl std::fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Vault response is larger than vault_max_response_bytes ({} bytes)",
+            self.limit
+        )
+    }
+}
+
+/// The [`ResponseTooLarge`] that stopped a read, if that is what `error` is.
+pub fn too_large(error: &ClientError) -> Option<&ResponseTooLarge> {
+    match error {
+        ClientError::RestClientError {
+            source: rustify::errors::ClientError::ResponseError { source },
+        } => source.downcast_ref(),
+        _ => None,
+    }
+}
+
+/// Sends requests like vaultrs' own client does, but gives up on a response as soon as more
+/// than `limit` bytes of it have arrived, instead of buffering all of it.
+struct Limited<'a> {
+    http: &'a HttpClient,
+    limit: usize,
+}
+
+#[async_trait]
+impl rustify::client::Client for Limited<'_> {
+    fn base(&self) -> &str {
+        &self.http.base
+    }
+
+    async fn send(
+        &self,
+        mut req: hyper::http::Request<Vec<u8>>,
+    ) -> Result<hyper::http::Response<Vec<u8>>, rustify::errors::ClientError> {
+        use rustify::errors::ClientError as RestError;
+
+        // Vault's side of the call joins the trace of the request it is made for
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(
+                &tracing::Span::current().context(),
+                &mut HeaderInjector(req.headers_mut()),
+            )
+        });
+        let request = reqwest::Request::try_from(req)
+            .map_err(|e| RestError::ReqwestBuildError { source: e })?;
+        let (url, method) = (request.url().to_string(), request.method().to_string());
+        let mut response =
+            self.http
+                .http
+                .execute(request)
+                .await
+                .map_err(|e| RestError::RequestError {
+                    source: e.into(),
+                    url,
+                    method,
+                })?;
+
+        let too_large = || RestError::ResponseError {
+            source: ResponseTooLarge { limit: self.limit }.into(),
+        };
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.limit as u64)
+        {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| RestError::ResponseError { source: e.into() })?
+        {
+            if body.len() + chunk.len() > self.limit {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let mut builder = hyper::http::Response::builder().status(response.status().as_u16());
+        for (name, value) in response.headers() {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(body)
+            .map_err(|e| RestError::ResponseError { source: e.into() })
+    }
+}
+
+/// Executes `endpoint` reading at most `limit` bytes of the response, failing with
+/// [`ResponseTooLarge`] beyond that.
+pub async fn exec_limited<E: Endpoint>(
+    client: &impl Client,
+    endpoint: E,
+    limit: usize,
+) -> Result<rustify::endpoint::EndpointResult<E::Response>, ClientError> {
+    let http = Limited {
+        http: client.http(),
+        limit,
+    };
+    endpoint
+        .with_middleware(client.middle())
+        .exec(&http)
+        .await
+        .map_err(api_error)
+}
+
+/// Parses the errors Vault lists in a failed response, as vaultrs does for its own calls.
+fn api_error(error: rustify::errors::ClientError) -> ClientError {
+    if let rustify::errors::ClientError::ServerResponseError {
+        code,
+        content: Some(content),
+    } = &error
+    {
+        if let Ok(EndpointError { errors }) = serde_json::from_str(content) {
+            return ClientError::APIError {
+                code: *code,
+                errors,
+            };
+        }
+    }
+    error.into()
+}
+
+pub fn reason(error: ClientError) -> String {
+    match error {
+        ClientError::APIError { code, errors } if !errors.is_empty() => {
+            format!("Vault returned {}: {}", code, errors.join(", "))
+        }
+        ClientError::APIError { code, .. } => format!("Vault returned {}", code),
+        e => format!("{:#}", anyhow::Error::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use opentelemetry::sdk::metrics::reader::{
+        AggregationSelector, MetricProducer, MetricReader, TemporalitySelector,
+    };
+    use opentelemetry::sdk::metrics::{
+        data, Aggregation, InstrumentKind, ManualReader, MeterProvider, Pipeline,
+    };
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn approle_login_uses_the_issued_token() {
+        let received = Arc::new(Mutex::new(None));
+        let vault = Router::new().route(
+            "/v1/auth/approle/login",
+            post({
+                let received = received.clone();
+                move |body: String| async move {
+                    *received.lock().unwrap() = serde_json::from_str::<Value>(&body).ok();
+                    let Json(mut response) = crate::vault_response(Value::Null);
+                    response["auth"] = json!({
+                        "client_token" : "hvs.from-approle",
+                        "accessor" : "",
+                        "policies" : ["billing"],
+                        "token_policies" : ["billing"],
+                        "metadata" : null,
+                        "lease_duration" : 3600,
+                        "renewable" : true,
+                        "entity_id" : "",
+                        "token_type" : "service",
+                        "orphan" : true,
+                    });
+                    Json(response)
+                }
+            }),
+        );
+        let settings = Settings {
+            vault_address: crate::fake_vault(vault).await,
+            vault_auth: Some(VaultAuth::AppRole {
+                role_id: "billing-role".into(),
+                secret_id: "s3cret".into(),
+                mount: "approle".into(),
+            }),
+            ..Settings::default()
+        };
+
+        let (client, lease) = connect(&settings).await.unwrap();
+
+        assert_eq!(
+            received.lock().unwrap().take().unwrap(),
+            json!({ "role_id" : "billing-role", "secret_id" : "s3cret" })
+        );
+        assert_eq!(client.settings.token, "hvs.from-approle");
+        assert_eq!(lease.ttl, Duration::from_secs(3600));
+        assert!(lease.renewable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connecting_is_retried_until_vault_is_reachable() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        let connect = || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0..=2 => Err(anyhow!("error sending request: connection refused")),
+                _ => Ok("connected"),
+            }
+        };
+
+        assert_eq!(retry_connect(5, connect).await.unwrap(), "connected");
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        attempts.store(0, Ordering::SeqCst);
+        let error = retry_connect(2, connect).await.unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(format!("{:#}", error).starts_with("giving up on Vault after 2 attempts"));
+    }
+
+    #[tokio::test]
+    async fn kubernetes_login_sends_the_service_account_token() {
+        let received = Arc::new(Mutex::new(None));
+        let vault = Router::new().route(
+            "/v1/auth/k8s/login",
+            post({
+                let received = received.clone();
+                move |body: String| async move {
+                    *received.lock().unwrap() = serde_json::from_str::<Value>(&body).ok();
+                    let Json(mut response) = crate::vault_response(Value::Null);
+                    response["auth"] = json!({
+                        "client_token" : "hvs.from-kubernetes",
+                        "accessor" : "",
+                        "policies" : ["billing"],
+                        "token_policies" : ["billing"],
+                        "metadata" : null,
+                        "lease_duration" : 3600,
+                        "renewable" : true,
+                        "entity_id" : "",
+                        "token_type" : "service",
+                        "orphan" : true,
+                    });
+                    Json(response)
+                }
+            }),
+        );
+        let token_path =
+            std::env::temp_dir().join(format!("spot-k8s-token-{}", std::process::id()));
+        std::fs::write(&token_path, "eyJhbGciOiJSUzI1NiJ9.service-account\n").unwrap();
+        let settings = Settings {
+            vault_address: crate::fake_vault(vault).await,
+            vault_auth: Some(VaultAuth::Kubernetes {
+                role: "billing".into(),
+                mount: "k8s".into(),
+                token_path,
+            }),
+            ..Settings::default()
+        };
+
+        let (client, lease) = connect(&settings).await.unwrap();
+
+        assert_eq!(
+            received.lock().unwrap().take().unwrap(),
+            json!({ "role" : "billing", "jwt" : "eyJhbGciOiJSUzI1NiJ9.service-account" })
+        );
+        assert_eq!(client.settings.token, "hvs.from-kubernetes");
+        assert_eq!(lease.ttl, Duration::from_secs(3600));
+    }
+
+    /// Lets the test keep the reader it gives the meter provider, to collect from it.
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl TemporalitySelector for SharedReader {
+        fn temporality(&self, kind: InstrumentKind) -> data::Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl AggregationSelector for SharedReader {
+        fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+            self.0.aggregation(kind)
+        }
+    }
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: std::sync::Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn register_producer(&self, producer: Box<dyn MetricProducer>) {
+            self.0.register_producer(producer)
+        }
+
+        fn collect(&self, rm: &mut data::ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self, cx: &opentelemetry::Context) -> opentelemetry::metrics::Result<()> {
+            self.0.force_flush(cx)
+        }
+
+        fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+            self.0.shutdown()
+        }
+    }
+
+    #[tokio::test]
+    async fn wrapped_call_records_its_duration() {
+        use opentelemetry::metrics::MeterProvider as _;
+
+        let reader = SharedReader(Arc::default());
+        let provider = MeterProvider::builder().with_reader(reader.clone()).build();
+        let metrics = VaultMetrics::new(&provider.meter("spot"));
+
+        measured(Some(&metrics), "kv2.read", async {
+            Ok::<_, ClientError>(())
+        })
+        .await
+        .unwrap();
+
+        let mut collected = data::ResourceMetrics {
+            resource: opentelemetry::sdk::Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut collected).unwrap();
+        let duration = collected.scope_metrics[0]
+            .metrics
+            .iter()
+            .find(|metric| metric.name == "vault.operation.duration")
+            .expect("the duration histogram");
+        let histogram = duration
+            .data
+            .as_any()
+            .downcast_ref::<data::Histogram<f64>>()
+            .unwrap();
+        let point = &histogram.data_points[0];
+        assert_eq!(point.count, 1);
+        assert!(point
+            .attributes
+            .iter()
+            .any(|(key, value)| key.as_str() == "operation" && value.as_str() == "kv2.read"));
+    }
+
+    #[tokio::test]
+    async fn calls_before_a_provider_is_installed_do_not_stop_later_ones_being_recorded() {
+        // Like decrypting the config, which happens before telemetry is set up
+        call("test.before_provider", async { Ok::<_, ClientError>(()) })
+            .await
+            .unwrap();
+        let reader = SharedReader(Arc::default());
+        let provider = MeterProvider::builder().with_reader(reader.clone()).build();
+        record_into(&provider);
+
+        call("test.after_provider", async { Ok::<_, ClientError>(()) })
+            .await
+            .unwrap();
+
+        let mut collected = data::ResourceMetrics {
+            resource: opentelemetry::sdk::Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut collected).unwrap();
+        let calls = collected.scope_metrics[0]
+            .metrics
+            .iter()
+            .find(|metric| metric.name == "vault.operations")
+            .expect("the call counter");
+        let sum = calls
+            .data
+            .as_any()
+            .downcast_ref::<data::Sum<u64>>()
+            .unwrap();
+        let operations: Vec<_> = sum
+            .data_points
+            .iter()
+            .flat_map(|point| point.attributes.iter())
+            .filter(|(key, _)| key.as_str() == "operation")
+            .map(|(_, value)| value.as_str().into_owned())
+            .collect();
+        // Other tests' calls land here too once the provider is installed
+        assert!(operations.contains(&"test.after_provider".to_owned()));
+        assert!(!operations.contains(&"test.before_provider".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn calls_are_counted_on_the_metrics_endpoint() {
+        let handle = crate::metrics::test_handle();
+
+        measured(None, "test.prometheus", async { Ok::<_, ClientError>(()) })
+            .await
+            .unwrap();
+
+        let rendered = handle.render();
+        assert!(
+            rendered
+                .contains(r#"vault_operations_total{operation="test.prometheus",outcome="ok"} 1"#),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains(
+            r#"vault_operation_duration_seconds_count{operation="test.prometheus",outcome="ok"} 1"#
+        ));
+    }
+
+    #[test]
+    fn namespace_is_set_on_the_client_settings() {
+        let settings = Settings {
+            vault_namespace: Some("team-a".into()),
+            ..Settings::default()
+        };
+
+        let client = client(&settings).unwrap();
+
+        assert_eq!(client.settings.namespace.as_deref(), Some("team-a"));
+        assert_eq!(client.middle.namespace.as_deref(), Some("team-a"));
+        let namespaced = Namespaced::new(Arc::new(client), Some("team-b"));
+        assert_eq!(namespaced.middle().namespace.as_deref(), Some("team-b"));
+    }
+
+    fn api_error(code: u16) -> ClientError {
+        ClientError::APIError {
+            code,
+            errors: vec![],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unavailable_vault_is_retried_until_it_answers() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result = call_with_retry("kv2.read", 3, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(api_error(503)),
+                _ => Ok("secret"),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), "secret");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forbidden_is_not_retried() {
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+
+        let result: Result<(), _> = call_with_retry("kv2.read", 3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(api_error(403))
+        })
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(ClientError::APIError { code: 403, .. })
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_is_bounded_by_the_request_deadline() {
+        let started = tokio::time::Instant::now();
+        let slow_vault = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok(())
+        };
+
+        let result = DEADLINE
+            .scope(
+                started + Duration::from_millis(200),
+                call("kv2.read", slow_vault),
+            )
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ClientError::APIError { code: 504, .. })
+        ));
+        assert_eq!(started.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_fails_fast_with_too_little_time_left() {
+        let deadline = tokio::time::Instant::now() + MIN_BUDGET / 2;
+        let started = std::sync::atomic::AtomicBool::new(false);
+
+        let result = DEADLINE
+            .scope(
+                deadline,
+                call("kv2.read", async {
+                    started.store(true, Ordering::SeqCst);
+                    Ok(())
+                }),
+            )
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ClientError::APIError { code: 504, .. })
+        ));
+        assert!(!started.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn pool_options_reflect_the_settings() {
+        let settings = Settings {
+            vault_pool_max_idle: Some(8),
+            vault_pool_idle_timeout_secs: Some(90),
+            vault_connect_timeout_secs: Some(3),
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            PoolOptions::new(&settings),
+            PoolOptions {
+                max_idle_per_host: Some(8),
+                idle_timeout: Some(Duration::from_secs(90)),
+                connect_timeout: Some(Duration::from_secs(3)),
+            }
+        );
+        assert_eq!(
+            PoolOptions::new(&Settings::default()),
+            PoolOptions::default()
+        );
+        client(&settings).expect("the pooled client builds");
+    }
+}
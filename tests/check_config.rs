@@ -0,0 +1,78 @@
+//! `spot --check-config` run as a process against good and bad configuration files.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn check_config(name: &str, contents: &str) -> Output {
+    let dir = std::env::temp_dir().join(format!("spot-check-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path: PathBuf = dir.join(name);
+    fs::write(&path, contents).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_spot"))
+        .arg("--check-config")
+        .arg("--config")
+        .arg(&path)
+        // Keep SPOT_* variables of the environment running the tests out of the configuration
+        .env_clear()
+        .output()
+        .expect("spot runs")
+}
+
+#[test]
+fn valid_config_is_printed_redacted() {
+    let output = check_config(
+        "valid.toml",
+        r#"
+        vault_address = "https://vault.example:8200"
+        vault_token = "hvs.CAESIJ-root-token"
+        api_token = "spot-api-token-value"
+        "#,
+    );
+
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(printed["vault_address"], "https://vault.example:8200");
+    assert_eq!(printed["vault_token"], "****oken");
+    assert!(!stdout.contains("hvs.CAESIJ-root-token"));
+    assert!(!stdout.contains("spot-api-token-value"));
+}
+
+#[test]
+fn invalid_config_fails_and_says_why() {
+    let output = check_config(
+        "invalid.toml",
+        r#"
+        vault_address = "https://vault.example:8200"
+        listen_addr = "not an address"
+        "#,
+    );
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid socket address"), "{}", stderr);
+}
+
+#[test]
+fn environment_alone_is_enough_without_a_config_file() {
+    let dir = std::env::temp_dir().join(format!("spot-check-config-env-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // No spot.{toml,yaml,json} in the working directory
+    let output = Command::new(env!("CARGO_BIN_EXE_spot"))
+        .arg("--check-config")
+        .current_dir(&dir)
+        .env_clear()
+        .env("SPOT_VAULT_ADDRESS", "https://vault.example:8200")
+        .env("SPOT_VAULT_TOKEN", "hvs.CAESIJ-root-token")
+        .env("SPOT_API_TOKEN", "spot-api-token-value")
+        .output()
+        .expect("spot runs");
+
+    assert!(output.status.success(), "{:?}", output);
+    let printed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(printed["vault_address"], "https://vault.example:8200");
+    assert_eq!(printed["vault_token"], "****oken");
+}